@@ -0,0 +1,195 @@
+//! Sends desktop notifications when something interesting happens: a
+//! temperature crosses a threshold, other software changes the fans, a fan
+//! stalls, or the server goes away. Run it from your session (e.g. as an
+//! autostart entry), since notifications go through the session bus.
+//!
+//! Usage: `a15kb-notifier [--threshold <celcius>] [--interval <seconds>]`
+use a15kb::{Celcius, Client, FanMode, FanState};
+use anyhow::Context;
+use dbus::arg::{PropMap, Variant};
+use dbus::blocking::Connection;
+use std::sync::mpsc;
+use std::time::Duration;
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+
+/// The default temperature to warn at.
+const DEFAULT_THRESHOLD: Celcius = 90;
+
+/// How far a temperature has to drop below the threshold before we'll warn
+/// about it again, so a temperature hovering around the threshold doesn't
+/// spam notifications.
+const HYSTERESIS: Celcius = 5;
+
+/// Describes a fan state for humans.
+fn describe(state: FanState) -> String {
+    match state.fan_mode {
+        FanMode::Fixed => format!("fixed at {}", state.fixed_fan_speed),
+        fan_mode => fan_mode.to_string(),
+    }
+}
+
+/// The urgency levels from the notification spec.
+#[derive(Clone, Copy)]
+enum Urgency {
+    Normal = 1,
+    Critical = 2,
+}
+
+/// A connection to the desktop's notification server.
+struct Notifier {
+    conn: Connection,
+}
+impl Notifier {
+    fn new() -> Result<Self, dbus::Error> {
+        Ok(Self {
+            conn: Connection::new_session()?,
+        })
+    }
+
+    fn notify(&self, urgency: Urgency, summary: &str, body: &str) {
+        let proxy = self.conn.with_proxy(
+            NOTIFICATIONS_NAME,
+            NOTIFICATIONS_PATH,
+            Duration::from_millis(1000),
+        );
+        let mut hints = PropMap::new();
+        hints.insert("urgency".into(), Variant(Box::new(urgency as u8)));
+        let result: Result<(u32,), _> = proxy.method_call(
+            NOTIFICATIONS_NAME,
+            "Notify",
+            (
+                "a15kb",
+                0u32,
+                "dialog-warning",
+                summary,
+                body,
+                Vec::<String>::new(),
+                hints,
+                -1i32,
+            ),
+        );
+        if let Err(err) = result {
+            eprintln!("error: couldn't send notification: {err}");
+        }
+    }
+}
+
+/// Remembers whether a temperature is currently over the threshold.
+struct TempWatch {
+    name: &'static str,
+    over: bool,
+}
+impl TempWatch {
+    fn update(&mut self, notifier: &Notifier, threshold: Celcius, temp: Celcius) {
+        if !self.over && temp >= threshold {
+            self.over = true;
+            notifier.notify(
+                Urgency::Critical,
+                &format!("{} is running hot", self.name),
+                &format!("The {} is at {temp}°C.", self.name),
+            );
+        } else if self.over && temp < threshold.saturating_sub(HYSTERESIS) {
+            self.over = false;
+        }
+    }
+}
+
+pub fn main() -> Result<(), anyhow::Error> {
+    let mut threshold = DEFAULT_THRESHOLD;
+    let mut interval = Duration::from_secs(2);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--threshold" => {
+                let value = args.next().context("--threshold needs a value")?;
+                threshold = value.parse().context("invalid threshold")?;
+            }
+            "--interval" => {
+                let value = args.next().context("--interval needs a value")?;
+                interval = Duration::from_secs(value.parse().context("invalid interval")?);
+            }
+            _ => anyhow::bail!("unknown argument \"{arg}\""),
+        }
+    }
+
+    let client = Client::new().context("failed to initialize client")?;
+    let notifier = Notifier::new().context("couldn't connect to session bus")?;
+
+    // Stall warnings arrive as signals, so hand them over to the main loop
+    let (stall_tx, stall_rx) = mpsc::channel();
+    client
+        .on_fan_stall_warning(move |fan, fixed_fan_speed| {
+            let _ = stall_tx.send((fan, fixed_fan_speed));
+        })
+        .context("couldn't listen for stall warnings")?;
+    let (change_tx, change_rx) = mpsc::channel();
+    client
+        .on_external_change(move |change| {
+            let _ = change_tx.send(change);
+        })
+        .context("couldn't listen for external changes")?;
+
+    let mut cpu = TempWatch {
+        name: "CPU",
+        over: false,
+    };
+    let mut gpu = TempWatch {
+        name: "GPU",
+        over: false,
+    };
+    let mut connected = true;
+    loop {
+        // Doubles as our sleep between checks
+        client.process(interval).context("lost the system bus")?;
+        for (fan, fixed_fan_speed) in stall_rx.try_iter() {
+            let side = if fan == 0 { "left" } else { "right" };
+            notifier.notify(
+                Urgency::Critical,
+                "A fan has stalled",
+                &format!(
+                    "The {side} fan isn't spinning, even though it's set to {fixed_fan_speed}."
+                ),
+            );
+        }
+        for change in change_rx.try_iter() {
+            let reverted = if change.reasserted {
+                " a15kb changed them back."
+            } else {
+                ""
+            };
+            notifier.notify(
+                Urgency::Normal,
+                "Something else changed the fans",
+                &format!(
+                    "The fans went from {} to {}.{reverted}",
+                    describe(change.before),
+                    describe(change.after)
+                ),
+            );
+        }
+
+        let info = match client.thermal_info() {
+            Ok(info) => info,
+            Err(err) => {
+                if connected {
+                    connected = false;
+                    notifier.notify(
+                        Urgency::Critical,
+                        "Lost the fan controller",
+                        &format!("The a15kb server isn't responding ({err}), so the fans aren't being managed."),
+                    );
+                }
+                continue;
+            }
+        };
+        if !connected {
+            connected = true;
+            notifier.notify(Urgency::Normal, "Fan controller is back", "");
+        }
+
+        cpu.update(&notifier, threshold, info.temp_cpu);
+        gpu.update(&notifier, threshold, info.temp_gpu);
+    }
+}
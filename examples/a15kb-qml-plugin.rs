@@ -85,7 +85,7 @@ impl QSingletonInit for QA15KBController {
                     // Potentially update fan state
                     let mut fan_state = obj.fan_state.lock().unwrap();
                     let new_fan_state = (
-                        client.fan_mode().unwrap_or_default().unwrap_or_default(),
+                        client.fan_mode().unwrap_or_default(),
                         client.fixed_fan_speed().unwrap_or_default(),
                     );
                     if *fan_state != new_fan_state {
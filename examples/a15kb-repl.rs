@@ -1,75 +1,418 @@
-use a15kb::{Client, FanMode, Percent};
-use anyhow::Context;
-
-fn parse_fan_mode(s: &str) -> Option<FanMode> {
-    match s {
-        "Quiet" => Some(FanMode::Quiet),
-        "Normal" => Some(FanMode::Normal),
-        "Gaming" => Some(FanMode::Gaming),
-        "Fixed" => Some(FanMode::Fixed),
-        _ => None,
+//! A small command language for talking to the server, either interactively
+//! (with line editing and history) or from a script, e.g. for testing fan
+//! behavior under load.
+//!
+//! Usage:
+//! - `a15kb-repl`: Reads commands interactively (or from stdin, if it isn't
+//!   a terminal).
+//! - `a15kb-repl <script>`: Runs the commands in a file, one per line.
+//! - `a15kb-repl -c <commands>`: Runs the given commands, separated by `;`.
+//!
+//! Commands are case-insensitive; run `help` for the list. When running a
+//! script (or `-c`), the first failing command stops it with a nonzero exit
+//! status. `#` starts a comment.
+use a15kb::{Client, FanMode, GpuTemp, Percent};
+use anyhow::{bail, Context};
+use rustyline::error::ReadlineError;
+use std::io::{BufRead, IsTerminal};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How often `wait-until` checks its condition.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Every command, as (usage, description).
+const COMMANDS: &[(&str, &str)] = &[
+    ("help [<command>]", "Lists the commands, or describes one."),
+    ("info", "Shows the temperatures and fan RPMs."),
+    ("sensors", "Shows every sensor the server knows about."),
+    (
+        "mode [<mode> [for <duration>]]",
+        "Shows or sets the fan mode (quiet, normal, gaming or fixed), optionally only for a while.",
+    ),
+    (
+        "speed [<speed>]",
+        "Shows or sets the fixed fan speed, as a percentage (55%) or fraction (0.55).",
+    ),
+    (
+        "rpm [<rpm>]",
+        "Shows or sets the fixed fan speed in RPM. Needs calibrated fans.",
+    ),
+    ("allowed", "Shows the allowed fixed fan speeds."),
+    ("caps", "Shows what the server and hardware support."),
+    (
+        "stats",
+        "Shows how much the server wrote to the EC, and how many calls it refused.",
+    ),
+    (
+        "sleep <duration>",
+        "Waits, e.g. `sleep 30s`. Durations are in seconds unless given a unit (ms, s, m or h).",
+    ),
+    (
+        "wait-until <condition> [<timeout>]",
+        "Waits until e.g. `temp<60` holds, failing after the timeout if one is given. \
+         Conditions compare temp (the hottest of cpu and gpu), cpu, gpu or rpm (the fastest fan) \
+         with <, <=, > or >=.",
+    ),
+    ("quit", "Exits (so does Ctrl-D)."),
+];
+
+/// Names the original REPL used, so old scripts keep working.
+const ALIASES: &[(&str, &str)] = &[
+    ("exit", "quit"),
+    ("getthermalinfo", "info"),
+    ("getfanmode", "mode"),
+    ("setfanmode", "mode"),
+    ("getfixedfanspeed", "speed"),
+    ("setfixedfanspeed", "speed"),
+    ("allowedfixedfanspeeds", "allowed"),
+    ("capabilities", "caps"),
+];
+
+/// Whether to keep reading commands.
+#[derive(PartialEq, Eq)]
+enum Flow {
+    Continue,
+    Quit,
+}
+
+/// Parses a fixed fan speed, either as a percentage (`55%`) or as a
+/// fraction (`0.55`).
+fn parse_speed(s: &str) -> anyhow::Result<Percent> {
+    let speed = match s.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>()? / 100.0,
+        None => s.parse::<f64>()?,
+    };
+    if speed > 1.0 && !s.ends_with('%') {
+        bail!("{s} is more than 100%; did you mean {s}%?");
     }
+    Percent::new(speed).with_context(|| format!("{s} is negative"))
 }
 
-pub fn main() -> Result<(), anyhow::Error> {
-    let client = Client::new().context("failed to initialize client")?;
-    for line in std::io::stdin().lines() {
-        match line.unwrap().as_str() {
-            "Quit" => break,
-            "AllowedFixedFanSpeeds" => match client.allowed_fixed_fan_speeds() {
-                Ok(range) => println!("{} to {}", range.start(), range.end()),
-                Err(err) => println!("error: {err}"),
-            },
-            "GetThermalInfo" => match client.thermal_info() {
-                Ok(info) => println!("{info:?}"),
-                Err(err) => println!("error: {err}"),
-            },
-            "GetFanMode" => match client.fan_mode() {
-                Ok(info) => println!("{info:?}"),
-                Err(err) => println!("error: {err}"),
+/// Parses a duration like `500ms`, `30s`, `2m` or `1h`. Plain numbers are
+/// seconds.
+fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value
+        .parse()
+        .with_context(|| format!("invalid duration \"{s}\""))?;
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => bail!("unknown unit \"{unit}\" in duration \"{s}\""),
+    };
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("invalid duration \"{s}\""))
+}
+
+/// A comparison operator in a `wait-until` condition.
+#[derive(Clone, Copy)]
+enum Op {
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+}
+
+/// A `wait-until` condition, e.g. `temp<60`.
+struct Condition {
+    subject: String,
+    op: Op,
+    value: f64,
+}
+
+impl Condition {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let start = s
+            .find(['<', '>'])
+            .with_context(|| format!("\"{s}\" isn't a comparison, like temp<60"))?;
+        let (subject, rest) = s.split_at(start);
+        let (op, value) = if let Some(value) = rest.strip_prefix("<=") {
+            (Op::LessEq, value)
+        } else if let Some(value) = rest.strip_prefix(">=") {
+            (Op::GreaterEq, value)
+        } else if let Some(value) = rest.strip_prefix('<') {
+            (Op::Less, value)
+        } else {
+            (Op::Greater, &rest[1..])
+        };
+        let subject = subject.trim().to_ascii_lowercase();
+        if !["temp", "cpu", "gpu", "rpm"].contains(&subject.as_str()) {
+            bail!("unknown value \"{subject}\"; try temp, cpu, gpu or rpm");
+        }
+        let value = value
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid number in \"{s}\""))?;
+        Ok(Self { subject, op, value })
+    }
+
+    /// Reads the condition's subject from the server.
+    fn current(&self, client: &Client) -> anyhow::Result<f64> {
+        let info = client.thermal_info()?;
+        // An unreadable dGPU doesn't count as hot (or cold)
+        let temp_gpu = match info.gpu {
+            GpuTemp::Temp(temp) => Some(temp),
+            GpuTemp::Off | GpuTemp::Unknown => None,
+        };
+        let value = match self.subject.as_str() {
+            "cpu" => info.temp_cpu.into(),
+            "gpu" => match temp_gpu {
+                Some(temp) => temp.into(),
+                None => bail!("the GPU's temperature isn't available"),
             },
-            a if a.starts_with("SetFanMode ") => {
-                let rest = a.strip_prefix("SetFanMode ").unwrap();
-                let fan_mode = match parse_fan_mode(rest) {
-                    Some(fan_mode) => fan_mode,
-                    None => {
-                        println!("error: unknown fan mode");
-                        continue;
-                    }
-                };
-                match client.set_fan_mode(fan_mode) {
-                    Ok(()) => println!("done"),
-                    Err(err) => println!("error: {err}"),
-                }
+            "temp" => info.temp_cpu.max(temp_gpu.unwrap_or(0)).into(),
+            _ => info.fan_rpm.0.max(info.fan_rpm.1).into(),
+        };
+        Ok(value)
+    }
+
+    fn holds(&self, current: f64) -> bool {
+        match self.op {
+            Op::Less => current < self.value,
+            Op::LessEq => current <= self.value,
+            Op::Greater => current > self.value,
+            Op::GreaterEq => current >= self.value,
+        }
+    }
+}
+
+/// Prints the list of commands, or the description of one.
+fn help(command: Option<&str>) -> anyhow::Result<()> {
+    let Some(command) = command else {
+        for (usage, _) in COMMANDS {
+            println!("  {usage}");
+        }
+        println!("Run `help <command>` for details.");
+        return Ok(());
+    };
+    let command = canonical(command);
+    let (usage, description) = COMMANDS
+        .iter()
+        .find(|(usage, _)| usage.split(' ').next() == Some(command.as_str()))
+        .with_context(|| format!("unknown command \"{command}\""))?;
+    println!("{usage}\n  {description}");
+    Ok(())
+}
+
+/// Lowercases a command name and resolves aliases.
+fn canonical(command: &str) -> String {
+    let command = command.to_ascii_lowercase();
+    match ALIASES.iter().find(|(alias, _)| *alias == command) {
+        Some((_, name)) => name.to_string(),
+        None => command,
+    }
+}
+
+/// Runs a single line's worth of commands (separated by `;`).
+fn run_line(client: &Client, line: &str) -> anyhow::Result<Flow> {
+    let line = line.split_once('#').map_or(line, |(line, _)| line);
+    for command in line.split(';') {
+        let args: Vec<_> = command.split_whitespace().collect();
+        if let Some((name, args)) = args.split_first() {
+            if run_command(client, &canonical(name), args)? == Flow::Quit {
+                return Ok(Flow::Quit);
             }
-            "GetFixedFanSpeed" => match client.fixed_fan_speed() {
-                Ok(info) => println!("{info:?}"),
-                Err(err) => println!("error: {err}"),
-            },
-            a if a.starts_with("SetFixedFanSpeed ") => {
-                let rest = a.strip_prefix("SetFixedFanSpeed ").unwrap();
-                let f = match rest.parse::<f64>() {
-                    Ok(f) => f,
-                    Err(err) => {
-                        println!("error: {err}");
-                        continue;
-                    }
-                };
-                let fixed_fan_speed = match Percent::try_from(f) {
-                    Ok(fixed_fan_speed) => fixed_fan_speed,
-                    Err(_) => {
-                        println!("error: not a percentage");
-                        continue;
-                    }
+        }
+    }
+    Ok(Flow::Continue)
+}
+
+fn run_command(client: &Client, name: &str, args: &[&str]) -> anyhow::Result<Flow> {
+    match (name, args) {
+        ("quit", []) => return Ok(Flow::Quit),
+        ("help", []) => help(None)?,
+        ("help", [command]) => help(Some(command))?,
+        ("info", []) => {
+            let info = client.thermal_info()?;
+            let gpu = match info.gpu {
+                GpuTemp::Off => "off".to_string(),
+                GpuTemp::Temp(temp) => format!("{temp}°C"),
+                GpuTemp::Unknown => "unknown".to_string(),
+            };
+            println!("CPU: {}°C", info.temp_cpu);
+            println!("GPU: {gpu}");
+            println!("Fans: {} RPM, {} RPM", info.fan_rpm.0, info.fan_rpm.1);
+        }
+        ("sensors", []) => {
+            for sensor in client.sensors()? {
+                let value = match sensor.value {
+                    Some(value) => format!("{value} {}", sensor.unit),
+                    None => "unreadable".to_string(),
                 };
-                match client.set_fixed_fan_speed(fixed_fan_speed) {
-                    Ok(()) => println!("done"),
-                    Err(err) => println!("error: {err}"),
-                }
+                println!("{}: {value} ({})", sensor.name, sensor.source);
             }
-            _ => println!("error: unknown command"),
+        }
+        ("mode", []) => println!("{}", client.fan_mode()?),
+        ("mode", [fan_mode]) => client.set_fan_mode(parse_fan_mode(fan_mode)?)?,
+        ("mode", [fan_mode, "for", duration]) => {
+            client.set_fan_mode_for(parse_fan_mode(fan_mode)?, parse_duration(duration)?)?
+        }
+        ("speed", []) => println!("{}", client.fixed_fan_speed()?),
+        ("speed", [speed]) => client.set_fixed_fan_speed(parse_speed(speed)?)?,
+        ("rpm", []) => {
+            let (left, right) = client.fixed_fan_rpm()?;
+            println!("{left} RPM, {right} RPM");
+        }
+        ("rpm", [rpm]) => {
+            let rpm = rpm
+                .parse()
+                .with_context(|| format!("invalid RPM \"{rpm}\""))?;
+            client.set_target_fan_rpm(rpm)?;
+        }
+        ("allowed", []) => {
+            let range = client.allowed_fixed_fan_speeds()?;
+            println!("{} to {}", range.start(), range.end());
+        }
+        ("caps", []) => {
+            let caps = client.capabilities()?;
+            let names = |names: &[String]| names.join(", ");
+            let fan_modes: Vec<_> = caps.fan_modes.iter().map(FanMode::to_string).collect();
+            println!(
+                "Server version: {}",
+                caps.server_version.as_deref().unwrap_or("?")
+            );
+            println!("Model: {}", caps.model.as_deref().unwrap_or("?"));
+            println!("Fan modes: {}", names(&fan_modes));
+            println!("Fans: {}", caps.fan_count);
+            println!("Per-fan control: {}", caps.per_fan_control);
+            println!("Sensors: {}", names(&caps.sensors));
+            let speeds = caps.fixed_fan_speeds;
+            println!("Fixed fan speeds: {} to {}", speeds.start(), speeds.end());
+            println!("Calibrated: {}", caps.calibrated);
+            println!("Read-only: {}", caps.read_only);
+            println!("Features: {}", names(&caps.features));
+            println!("Subsystems: {}", names(&caps.subsystems));
+        }
+        ("stats", []) => {
+            let stats = client.stats()?;
+            println!("EC bytes written: {}", stats.ec_bytes_written);
+            println!("EC bytes skipped: {}", stats.ec_bytes_skipped);
+            println!("Coalesced writes: {}", stats.coalesced_writes);
+            println!("Rate-limited calls: {}", stats.rate_limited_calls);
+        }
+        ("sleep", [duration]) => std::thread::sleep(parse_duration(duration)?),
+        ("wait-until", [condition]) => wait_until(client, condition, None)?,
+        ("wait-until", [condition, timeout]) => {
+            wait_until(client, condition, Some(parse_duration(timeout)?))?
+        }
+        _ if COMMANDS
+            .iter()
+            .any(|(usage, _)| usage.split(' ').next() == Some(name)) =>
+        {
+            bail!("wrong arguments; run `help {name}`")
+        }
+        _ => bail!("unknown command \"{name}\"; run `help` for the list"),
+    }
+    Ok(Flow::Continue)
+}
+
+fn parse_fan_mode(s: &str) -> anyhow::Result<FanMode> {
+    s.parse().map_err(|_| {
+        anyhow::anyhow!("unknown fan mode \"{s}\"; try quiet, normal, gaming or fixed")
+    })
+}
+
+fn wait_until(client: &Client, condition: &str, timeout: Option<Duration>) -> anyhow::Result<()> {
+    let condition = Condition::parse(condition)?;
+    let start = Instant::now();
+    loop {
+        let current = condition.current(client)?;
+        if condition.holds(current) {
+            return Ok(());
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            bail!("timed out ({} is {current})", condition.subject);
+        }
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+    }
+}
+
+/// Where interactive history is kept between sessions.
+fn history_path() -> Option<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_dir.join("a15kb-repl-history"))
+}
+
+/// Reads commands from the terminal until the user quits. Errors are
+/// printed, but don't stop anything.
+fn interactive(client: &Client) -> anyhow::Result<()> {
+    let mut editor = rustyline::DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(history) = &history {
+        // It's fine if there's no history yet
+        let _ = editor.load_history(history);
+    }
+    println!("Type `help` for a list of commands.");
+    loop {
+        let line = match editor.readline("a15kb> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
         };
+        let _ = editor.add_history_entry(&line);
+        match run_line(client, &line) {
+            Ok(Flow::Continue) => {}
+            Ok(Flow::Quit) => break,
+            Err(err) => println!("error: {err:#}"),
+        }
     }
+    if let Some(history) = &history {
+        if let Some(dir) = history.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        if let Err(err) = editor.save_history(history) {
+            eprintln!("warning: couldn't save history: {err}");
+        }
+    }
+    Ok(())
+}
 
+/// Runs commands from a script, stopping at the first error.
+fn script(
+    client: &Client,
+    name: &str,
+    lines: impl Iterator<Item = std::io::Result<String>>,
+) -> anyhow::Result<()> {
+    for (number, line) in lines.enumerate() {
+        let line = line.with_context(|| format!("couldn't read {name}"))?;
+        let flow = run_line(client, &line)
+            .with_context(|| format!("{name}:{}: {}", number + 1, line.trim()))?;
+        if flow == Flow::Quit {
+            break;
+        }
+    }
     Ok(())
 }
+
+pub fn main() -> Result<(), anyhow::Error> {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if let [flag] = &args[..] {
+        if flag == "-h" || flag == "--help" {
+            println!("usage: a15kb-repl [<script> | -c <commands>]");
+            return help(None);
+        }
+    }
+    let client = Client::new().context("failed to initialize client")?;
+    match &args[..] {
+        [] if std::io::stdin().is_terminal() => interactive(&client),
+        [] => script(&client, "stdin", std::io::stdin().lock().lines()),
+        [flag, commands] if flag == "-c" => {
+            script(&client, "-c", std::iter::once(Ok(commands.clone())))
+        }
+        [path] => {
+            let file =
+                std::fs::File::open(path).with_context(|| format!("couldn't open {path}"))?;
+            script(&client, path, std::io::BufReader::new(file).lines())
+        }
+        _ => bail!("usage: a15kb-repl [<script> | -c <commands>]"),
+    }
+}
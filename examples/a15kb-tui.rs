@@ -0,0 +1,293 @@
+//! A terminal dashboard: live temperature and fan RPM sparklines, the current
+//! fan mode, and keyboard shortcuts to change it. It only needs a terminal,
+//! so it works fine over SSH (e.g. on a laptop that's being used as a build
+//! box with the lid closed).
+//!
+//! Usage: `a15kb-tui [--interval <milliseconds>]`
+//!
+//! Keys: `1`-`4` switch to quiet/normal/gaming/fixed, `+`/`-` (or the arrow
+//! keys) adjust the fixed fan speed, and `q` quits.
+use a15kb::{Client, FanMode, GpuTemp, Percent, ThermalInfo};
+use anyhow::Context;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Sparkline};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// How many samples to keep for each sparkline. Anything wider than this
+/// just shows a shorter history.
+const HISTORY_LEN: usize = 512;
+
+/// How much `+` and `-` change the fixed fan speed by.
+const SPEED_STEP: f64 = 0.05;
+
+/// The last few values of something, oldest first.
+#[derive(Default)]
+struct History(VecDeque<u64>);
+impl History {
+    fn push(&mut self, value: u64) {
+        if self.0.len() == HISTORY_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back(value);
+    }
+
+    fn last(&self) -> Option<u64> {
+        self.0.back().copied()
+    }
+
+    /// Returns the newest samples which fit in `width` columns.
+    fn tail(&self, width: u16) -> impl Iterator<Item = u64> + '_ {
+        let skip = self.0.len().saturating_sub(width.into());
+        self.0.iter().skip(skip).copied()
+    }
+}
+
+/// Everything on screen.
+struct Dashboard {
+    client: Client,
+    allowed_speeds: Option<RangeInclusive<Percent>>,
+    temp_cpu: History,
+    temp_gpu: History,
+    gpu: GpuTemp,
+    rpm_left: History,
+    rpm_right: History,
+    fan_mode: Option<FanMode>,
+    fixed_fan_speed: Option<Percent>,
+    /// The result of the last thing we did, or the last error.
+    status: String,
+}
+
+impl Dashboard {
+    fn new(client: Client) -> Self {
+        Self {
+            client,
+            allowed_speeds: None,
+            temp_cpu: History::default(),
+            temp_gpu: History::default(),
+            gpu: GpuTemp::Unknown,
+            rpm_left: History::default(),
+            rpm_right: History::default(),
+            fan_mode: None,
+            fixed_fan_speed: None,
+            status: String::new(),
+        }
+    }
+
+    /// Polls the server for new values.
+    fn refresh(&mut self) {
+        match self.client.thermal_info() {
+            Ok(info) => {
+                let ThermalInfo {
+                    temp_cpu,
+                    temp_gpu,
+                    gpu,
+                    fan_rpm: (left, right),
+                } = info;
+                self.temp_cpu.push(temp_cpu.into());
+                self.temp_gpu.push(temp_gpu.into());
+                self.gpu = gpu;
+                self.rpm_left.push(left.into());
+                self.rpm_right.push(right.into());
+            }
+            Err(err) => {
+                // Keep going, so the dashboard picks up where it left off
+                // once the server is back
+                self.status = format!("couldn't reach the server: {err}");
+                self.fan_mode = None;
+                self.fixed_fan_speed = None;
+                return;
+            }
+        }
+        self.fan_mode = self.client.fan_mode().ok();
+        self.fixed_fan_speed = self.client.fixed_fan_speed().ok();
+        if self.allowed_speeds.is_none() {
+            self.allowed_speeds = self.client.allowed_fixed_fan_speeds().ok();
+        }
+    }
+
+    fn set_fan_mode(&mut self, fan_mode: FanMode) {
+        self.status = match self.client.set_fan_mode(fan_mode) {
+            Ok(()) => format!("switched to {fan_mode}"),
+            Err(err) => format!("couldn't switch to {fan_mode}: {err}"),
+        };
+        self.fan_mode = self.client.fan_mode().ok();
+    }
+
+    /// Moves the fixed fan speed up or down a step, staying within the
+    /// allowed range.
+    fn adjust_speed(&mut self, step: f64) {
+        let (Some(speed), Some(allowed)) = (self.fixed_fan_speed, &self.allowed_speeds) else {
+            self.status = "the fixed fan speed isn't known yet".to_string();
+            return;
+        };
+        let speed = (speed.as_f64() + step).clamp(allowed.start().as_f64(), allowed.end().as_f64());
+        let speed = Percent::new(speed).unwrap();
+        self.status = match self.client.set_fixed_fan_speed(speed) {
+            Ok(()) => format!("set the fixed fan speed to {speed}"),
+            Err(err) => format!("couldn't set the fixed fan speed: {err}"),
+        };
+        self.fixed_fan_speed = self.client.fixed_fan_speed().ok();
+    }
+
+    /// Handles a key press, returning whether to quit.
+    fn key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return true,
+            KeyCode::Char('1') => self.set_fan_mode(FanMode::Quiet),
+            KeyCode::Char('2') => self.set_fan_mode(FanMode::Normal),
+            KeyCode::Char('3') => self.set_fan_mode(FanMode::Gaming),
+            KeyCode::Char('4') => self.set_fan_mode(FanMode::Fixed),
+            KeyCode::Char('+' | '=') | KeyCode::Up | KeyCode::Right => {
+                self.adjust_speed(SPEED_STEP)
+            }
+            KeyCode::Char('-') | KeyCode::Down | KeyCode::Left => self.adjust_speed(-SPEED_STEP),
+            _ => {}
+        }
+        false
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, cpu, gpu, left, right, footer] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+            Constraint::Length(2),
+        ])
+        .areas(frame.area());
+
+        let fan_mode = match self.fan_mode {
+            Some(fan_mode) => fan_mode.to_string(),
+            None => "?".to_string(),
+        };
+        let fixed_fan_speed = match self.fixed_fan_speed {
+            Some(speed) => format!("{:.0}%", speed.as_f64() * 100.0),
+            None => "?".to_string(),
+        };
+        let allowed = match &self.allowed_speeds {
+            Some(allowed) => format!(
+                " ({:.0}%-{:.0}%)",
+                allowed.start().as_f64() * 100.0,
+                allowed.end().as_f64() * 100.0
+            ),
+            None => String::new(),
+        };
+        let summary = Line::from(vec![
+            "Fan mode: ".into(),
+            fan_mode.bold(),
+            "   Fixed speed: ".into(),
+            fixed_fan_speed.bold(),
+            allowed.into(),
+        ]);
+        frame.render_widget(
+            Paragraph::new(summary).block(Block::bordered().title(" a15kb ")),
+            header,
+        );
+
+        let gpu_title = match self.gpu {
+            GpuTemp::Off => " GPU: off ".to_string(),
+            GpuTemp::Temp(temp) => format!(" GPU: {temp}°C "),
+            GpuTemp::Unknown => " GPU: unknown ".to_string(),
+        };
+        let cpu_title = title("CPU", &self.temp_cpu, "°C");
+        let left_title = title("Left fan", &self.rpm_left, " RPM");
+        let right_title = title("Right fan", &self.rpm_right, " RPM");
+        sparkline(frame, cpu, &self.temp_cpu, cpu_title, Color::Red, 100);
+        sparkline(frame, gpu, &self.temp_gpu, gpu_title, Color::Magenta, 100);
+        sparkline(frame, left, &self.rpm_left, left_title, Color::Cyan, 0);
+        sparkline(frame, right, &self.rpm_right, right_title, Color::Cyan, 0);
+
+        let help =
+            Line::from("1 quiet  2 normal  3 gaming  4 fixed  +/- fixed speed  q quit".dark_gray());
+        frame.render_widget(
+            Paragraph::new(vec![Line::from(self.status.as_str()), help]),
+            footer,
+        );
+    }
+}
+
+/// Titles a sparkline with its latest value.
+fn title(name: &str, history: &History, unit: &str) -> String {
+    match history.last() {
+        Some(value) => format!(" {name}: {value}{unit} "),
+        None => format!(" {name} "),
+    }
+}
+
+/// Draws a sparkline of the newest values which fit. A `max` of 0 scales the
+/// sparkline to its highest value.
+fn sparkline(
+    frame: &mut Frame,
+    area: Rect,
+    history: &History,
+    title: String,
+    color: Color,
+    max: u64,
+) {
+    let block = Block::bordered().title(title);
+    let width = block.inner(area).width;
+    let mut sparkline = Sparkline::default()
+        .block(block)
+        .style(Style::default().fg(color))
+        .data(history.tail(width));
+    if max > 0 {
+        sparkline = sparkline.max(max);
+    }
+    frame.render_widget(sparkline, area);
+}
+
+fn run(
+    terminal: &mut DefaultTerminal,
+    dashboard: &mut Dashboard,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let mut next_refresh = Instant::now();
+    loop {
+        if Instant::now() >= next_refresh {
+            dashboard.refresh();
+            next_refresh = Instant::now() + interval;
+        }
+        terminal.draw(|frame| dashboard.draw(frame))?;
+
+        // Wait for a key press until it's time to refresh again
+        let timeout = next_refresh.saturating_duration_since(Instant::now());
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && dashboard.key(key.code, key.modifiers) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+pub fn main() -> Result<(), anyhow::Error> {
+    let mut interval = Duration::from_millis(1000);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interval" => {
+                let value = args.next().context("--interval needs a value")?;
+                interval = Duration::from_millis(value.parse().context("invalid interval")?);
+            }
+            _ => anyhow::bail!("unknown argument \"{arg}\""),
+        }
+    }
+
+    let client = Client::new().context("failed to initialize client")?;
+    let mut dashboard = Dashboard::new(client);
+
+    // This also restores the terminal if we panic
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut dashboard, interval);
+    ratatui::restore();
+    result
+}
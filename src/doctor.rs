@@ -0,0 +1,358 @@
+//! `a15kb doctor`: checks everything the server needs, one thing at a time,
+//! so it's clear what's wrong when it won't start, and how to fix it. The
+//! report can also be printed as JSON, to attach to bug reports.
+use dbus::blocking::Connection;
+use serde::Serialize;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// Where `ec_sys` exposes the embedded controller.
+const EC_PATH: &str = "/sys/kernel/debug/ec/ec0/io";
+
+/// How a check went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    /// Not a problem by itself, but worth knowing about.
+    Warn,
+    /// The server won't work until this is fixed.
+    Fail,
+}
+
+/// The outcome of a single check.
+#[derive(Debug, Serialize)]
+struct Finding {
+    check: &'static str,
+    status: Status,
+    /// What was found.
+    detail: String,
+    /// How to fix it, if it's a problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<&'static str>,
+}
+
+impl Finding {
+    fn ok(detail: impl Into<String>) -> Self {
+        Self {
+            check: "",
+            status: Status::Ok,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            status: Status::Warn,
+            hint: Some(hint),
+            ..Self::ok(detail)
+        }
+    }
+
+    fn fail(detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            status: Status::Fail,
+            hint: Some(hint),
+            ..Self::ok(detail)
+        }
+    }
+}
+
+/// Everything `doctor` found, along with what's needed to make sense of it.
+#[derive(Debug, Serialize)]
+struct Report {
+    version: &'static str,
+    kernel: String,
+    checks: Vec<Finding>,
+}
+
+/// A check, and its name.
+type Check = (&'static str, fn() -> Finding);
+
+/// The checks, in the order they're run.
+const CHECKS: &[Check] = &[
+    ("product", check_product),
+    ("board", check_board),
+    ("lockdown", check_lockdown),
+    ("ec_sys", check_ec_sys),
+    ("write_support", check_write_support),
+    ("debugfs", check_debugfs),
+    ("ec_file", check_ec_file),
+    ("bus_policy", check_bus_policy),
+    ("bus_name", check_bus_name),
+    ("service", check_service),
+];
+
+/// Runs every check and prints the results, as JSON if `json` is set.
+/// Fails if any check did.
+pub fn doctor(json: bool) -> Result<(), anyhow::Error> {
+    let report = Report {
+        version: env!("CARGO_PKG_VERSION"),
+        kernel: read("/proc/sys/kernel/osrelease").unwrap_or_else(|err| err.to_string()),
+        checks: CHECKS
+            .iter()
+            .map(|(name, check)| Finding {
+                check: name,
+                ..check()
+            })
+            .collect(),
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("a15kb {} on Linux {}", report.version, report.kernel);
+        for finding in &report.checks {
+            let status = match finding.status {
+                Status::Ok => "[ok]  ",
+                Status::Warn => "[warn]",
+                Status::Fail => "[fail]",
+            };
+            println!("{status} {}: {}", finding.check, finding.detail);
+            if let Some(hint) = finding.hint {
+                println!("       {hint}");
+            }
+        }
+    }
+    let failed = report
+        .checks
+        .iter()
+        .filter(|finding| finding.status == Status::Fail)
+        .count();
+    anyhow::ensure!(failed == 0, "{failed} of {} checks failed", CHECKS.len());
+    Ok(())
+}
+
+/// Reads a (small, textual) file, trimmed.
+fn read(path: &str) -> std::io::Result<String> {
+    Ok(std::fs::read_to_string(path)?.trim().to_string())
+}
+
+/// Runs `program` with `args`, returning what it printed.
+fn output(program: &str, args: &[&str]) -> std::io::Result<String> {
+    let output = Command::new(program).args(args).output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn check_product() -> Finding {
+    let (vendor, product) = match (
+        read("/sys/class/dmi/id/sys_vendor"),
+        read("/sys/class/dmi/id/product_name"),
+    ) {
+        (Ok(vendor), Ok(product)) => (vendor, product),
+        (Err(err), _) | (_, Err(err)) => {
+            return Finding::fail(
+                format!("couldn't read DMI: {err}"),
+                "a15kb needs DMI to recognize the laptop; is /sys mounted?",
+            )
+        }
+    };
+    match product.as_str() {
+        "AERO 15 KB" => Finding::ok(format!("{vendor} {product}")),
+        _ => Finding::fail(
+            format!("{vendor} {product} isn't supported"),
+            "a15kb only supports the Aero 15 KB. Other models may lay out their embedded \
+             controller differently, so the server refuses to start on them.",
+        ),
+    }
+}
+
+fn check_board() -> Finding {
+    match (
+        read("/sys/class/dmi/id/board_vendor"),
+        read("/sys/class/dmi/id/board_name"),
+    ) {
+        (Ok(vendor), Ok(name)) => Finding::ok(format!("{vendor} {name}")),
+        (Err(err), _) | (_, Err(err)) => Finding::warn(
+            format!("couldn't read DMI: {err}"),
+            "The board isn't checked, but it's useful to know in bug reports.",
+        ),
+    }
+}
+
+fn check_lockdown() -> Finding {
+    // e.g. "none [integrity] confidentiality"
+    let modes = match read("/sys/kernel/security/lockdown") {
+        Ok(modes) => modes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Finding::ok("not supported by this kernel")
+        }
+        Err(err) => {
+            return Finding::warn(
+                format!("couldn't read lockdown status: {err}"),
+                "Run `a15kb doctor` as root to check this.",
+            )
+        }
+    };
+    let mode = modes
+        .split_whitespace()
+        .find_map(|mode| mode.strip_prefix('[')?.strip_suffix(']'))
+        .unwrap_or("unknown");
+    match mode {
+        "none" => Finding::ok("off"),
+        _ => Finding::fail(
+            format!("{mode} mode"),
+            "Kernel lockdown (usually turned on by Secure Boot) keeps anyone from writing to the \
+             embedded controller through debugfs. Turn off Secure Boot, or boot with lockdown=none.",
+        ),
+    }
+}
+
+fn check_ec_sys() -> Finding {
+    if Path::new("/sys/module/ec_sys").exists() {
+        return Finding::ok("loaded");
+    }
+    match output("modinfo", &["--field=filename", "ec_sys"]) {
+        Ok(filename) if !filename.is_empty() => {
+            Finding::ok(format!("not loaded yet, but available ({filename})"))
+        }
+        Ok(_) => Finding::fail(
+            "not available",
+            "Your kernel doesn't come with ec_sys. It has to be built with \
+             CONFIG_ACPI_EC_DEBUGFS; some distributions ship it in an extra modules package.",
+        ),
+        Err(err) => Finding::fail(
+            format!("couldn't run modinfo: {err}"),
+            "The server loads ec_sys with modprobe, so kmod has to be installed.",
+        ),
+    }
+}
+
+fn check_write_support() -> Finding {
+    match read("/sys/module/ec_sys/parameters/write_support") {
+        Ok(enabled) if enabled == "Y" => Finding::ok("enabled"),
+        Ok(_) => Finding::fail(
+            "disabled",
+            "ec_sys was loaded without write support, so the fans can't be changed. Run \
+             `sudo modprobe -r ec_sys`, then restart the server, which loads it with \
+             write_support=1. If something else loads ec_sys at boot, make it pass write_support=1 too.",
+        ),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            Finding::ok("ec_sys isn't loaded yet, the server loads it with write support")
+        }
+        Err(err) => Finding::warn(
+            format!("couldn't read the write_support parameter: {err}"),
+            "Run `a15kb doctor` as root to check this.",
+        ),
+    }
+}
+
+fn check_debugfs() -> Finding {
+    let mounts = match read("/proc/mounts") {
+        Ok(mounts) => mounts,
+        Err(err) => {
+            return Finding::warn(
+                format!("couldn't read /proc/mounts: {err}"),
+                "Is /proc mounted?",
+            )
+        }
+    };
+    let mounted = mounts
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some("/sys/kernel/debug"));
+    match mounted {
+        true => Finding::ok("mounted at /sys/kernel/debug"),
+        false => Finding::fail(
+            "not mounted at /sys/kernel/debug",
+            "ec_sys exposes the embedded controller through debugfs. Mount it with \
+             `sudo mount -t debugfs none /sys/kernel/debug`, or enable sys-kernel-debug.mount.",
+        ),
+    }
+}
+
+fn check_ec_file() -> Finding {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    match std::fs::metadata(EC_PATH) {
+        // ec_sys only makes the file writable with write support
+        Ok(metadata) if metadata.permissions().mode() & 0o200 != 0 => Finding::ok(format!(
+            "{EC_PATH} is writable by uid {} (mode {:o})",
+            metadata.uid(),
+            metadata.permissions().mode() & 0o777
+        )),
+        Ok(metadata) => Finding::fail(
+            format!(
+                "{EC_PATH} is read-only (mode {:o})",
+                metadata.permissions().mode() & 0o777
+            ),
+            "ec_sys was loaded without write support; see write_support.",
+        ),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Finding::warn(
+            format!("{EC_PATH} doesn't exist"),
+            "That's expected if ec_sys isn't loaded yet. Otherwise, check that debugfs is \
+             mounted and that the kernel found an embedded controller.",
+        ),
+        Err(err) => Finding::warn(
+            format!("couldn't access {EC_PATH}: {err}"),
+            "Only root can see into debugfs, so run `a15kb doctor` as root to check this.",
+        ),
+    }
+}
+
+fn check_bus_policy() -> Finding {
+    let dirs = [
+        "/usr/share/dbus-1/system.d",
+        "/usr/local/share/dbus-1/system.d",
+        "/etc/dbus-1/system.d",
+    ];
+    let policy = dirs
+        .iter()
+        .map(|dir| Path::new(dir).join("com.offbyond.a15kb.conf"))
+        .find(|path| path.exists());
+    match policy {
+        Some(path) => Finding::ok(path.display().to_string()),
+        None => Finding::fail(
+            "not installed",
+            "Without the policy, the bus won't let the server take its name. Run `sudo a15kb install`.",
+        ),
+    }
+}
+
+fn check_bus_name() -> Finding {
+    let owner = || -> Result<Option<(String, u32)>, dbus::Error> {
+        let cxn = Connection::new_system()?;
+        let proxy = cxn.with_proxy("org.freedesktop.DBus", "/", Duration::from_secs(1));
+        let (has_owner,): (bool,) =
+            proxy.method_call("org.freedesktop.DBus", "NameHasOwner", (crate::BUS_NAME,))?;
+        if !has_owner {
+            return Ok(None);
+        }
+        let (owner,): (String,) =
+            proxy.method_call("org.freedesktop.DBus", "GetNameOwner", (crate::BUS_NAME,))?;
+        let (pid,): (u32,) = proxy.method_call(
+            "org.freedesktop.DBus",
+            "GetConnectionUnixProcessID",
+            (&owner,),
+        )?;
+        Ok(Some((owner, pid)))
+    };
+    match owner() {
+        Ok(Some((owner, pid))) => Finding::ok(format!("owned by {owner} (pid {pid})")),
+        Ok(None) => Finding::fail(
+            format!("nobody owns {}", crate::BUS_NAME),
+            "The server isn't running; see service.",
+        ),
+        Err(err) => Finding::fail(
+            format!("couldn't ask the system bus: {err}"),
+            "Clients talk to the server over the system bus, so it has to be running.",
+        ),
+    }
+}
+
+fn check_service() -> Finding {
+    match output("systemctl", &["is-active", crate::install::SERVICE]) {
+        Ok(state) if state == "active" => Finding::ok("active"),
+        Ok(state) => Finding::fail(
+            if state.is_empty() {
+                "unknown".into()
+            } else {
+                state
+            },
+            "Check why with `journalctl -u a15kb`, or install it with `sudo a15kb install`.",
+        ),
+        Err(err) => Finding::fail(
+            format!("couldn't run systemctl: {err}"),
+            "The server is meant to run as a systemd service.",
+        ),
+    }
+}
@@ -4,6 +4,9 @@ use std::io;
 use std::io::{Read, Seek, Write};
 use std::process::Command;
 
+mod sim;
+pub use sim::{SimCfg, SimStepCfg};
+
 macro_rules! ec_error {
     ($($tok:tt)*) => {
         {
@@ -15,6 +18,8 @@ macro_rules! ec_error {
         }
     };
 }
+// So the simulation can use it too, despite being declared first
+use ec_error;
 /// An error which occurred at the level of the embedded controller. This is
 /// opaque, which is fine, since there's nothing you can really *do* about an
 /// EC error (at least from userspace)
@@ -43,6 +48,12 @@ pub const FAN_FIXED_SPEED_MIN: f64 = 0.3;
 /// The maximum allowable fixed fan speed
 pub const FAN_FIXED_SPEED_MAX: f64 = 1.0;
 
+/// The EC offset of the CPU temperature, for [`Ec::temp_at`].
+pub const TEMP_CPU_OFFSET: u8 = offs::TEMP_CPU as u8;
+
+/// The EC offset of the dGPU temperature, for [`Ec::temp_at`].
+pub const TEMP_GPU_OFFSET: u8 = offs::TEMP_GPU as u8;
+
 /// Offsets (and possibly bit indices) of EC registers.
 mod offs {
     /// Byte. The CPU temperature, in degrees celcius.
@@ -72,17 +83,44 @@ mod offs {
 /// Convienence type.
 type EcResult<T> = Result<T, EcError>;
 
+/// Where the embedded controller's memory actually lives.
+enum Backend {
+    /// The real embedded controller, represented as a file.
+    Hardware(fs::File),
+    /// A simulated one, for development.
+    Simulated(Box<sim::Simulator>),
+}
+
+/// How many bytes have been written to the embedded controller.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WriteStats {
+    /// Bytes actually written.
+    pub written: u64,
+    /// Bytes which weren't written because they already had the right value.
+    pub skipped: u64,
+}
+
 /// A wrapper around the embedded controller.
 pub struct Ec {
-    /// The embedded controller's memory, represented as a file.
-    inner: fs::File,
+    inner: Backend,
+    /// The fan modes we last wrote, so that changes made by other software
+    /// can be noticed.
+    written_fan_modes: (bool, bool, bool),
+    /// The fixed hardware speeds we last wrote.
+    written_fan_fixed_hw_speeds: (u8, u8),
+    /// If set, writes are only logged.
+    read_only: bool,
+    stats: WriteStats,
 }
 
 impl Ec {
     /// Initializes a new controller instance. This uses `modprobe` to load
     /// `ec_sys` if it's not already loaded. This will fail if the system
     /// doesn't report itself to be "AERO 15 KB".
-    pub fn new() -> Result<Self, anyhow::Error> {
+    ///
+    /// If `read_only` is set, `ec_sys` is loaded without write support and
+    /// nothing is ever written; writes are logged instead.
+    pub fn new(read_only: bool) -> Result<Self, anyhow::Error> {
         // Before we do anything else, make sure we're actually running on an
         // Aero 15 KB.
         //
@@ -93,6 +131,7 @@ impl Ec {
         //
         // If you're have a different Aero model and want to run this anyways,
         // you can disable the safety check. Caveat emptor.
+        #[allow(clippy::non_minimal_cfg)]
         #[cfg(all())]
         {
             let product_name = fs::read_to_string("/sys/class/dmi/id/product_name")
@@ -106,21 +145,75 @@ impl Ec {
         // Load ec_sys kernel module so we can directly access the embedded
         // controller. I've heard rumors that ec_sys should be avoided, but
         // never any explanation...
-        let status = Command::new("modprobe")
-            .arg("ec_sys")
-            .arg("write_support=1")
+        let mut modprobe = Command::new("modprobe");
+        modprobe.arg("ec_sys");
+        if !read_only {
+            modprobe.arg("write_support=1");
+        }
+        let status = modprobe
             .status()
             .context("couldn't load ec_sys kernel module")?;
         ensure!(status.success(), "couldn't load ec_sys kernel module");
+        // If ec_sys was already loaded without write support (e.g. by a
+        // read-only run), modprobe does nothing, so reload it
+        if !read_only && !Self::write_support()? {
+            eprintln!("[info] ec_sys was loaded without write support, reloading it");
+            let unloaded = Command::new("modprobe")
+                .args(["-r", "ec_sys"])
+                .status()
+                .is_ok_and(|status| status.success());
+            let reloaded = unloaded
+                && Command::new("modprobe")
+                    .args(["ec_sys", "write_support=1"])
+                    .status()
+                    .is_ok_and(|status| status.success());
+            ensure!(
+                reloaded && Self::write_support()?,
+                "ec_sys was loaded without write support and couldn't be reloaded; run \
+                 `sudo modprobe -r ec_sys` and try again"
+            );
+        }
 
         // Open handle to embedded controller
         let inner = fs::File::options()
             .read(true)
-            .write(true)
+            .write(!read_only)
             .open("/sys/kernel/debug/ec/ec0/io")
             .context("couldn't access embedded controller")?;
 
-        Ok(Self { inner })
+        Self::with_backend(Backend::Hardware(inner), read_only)
+    }
+
+    /// Returns whether `ec_sys` was loaded with write support.
+    fn write_support() -> Result<bool, anyhow::Error> {
+        let enabled = fs::read_to_string("/sys/module/ec_sys/parameters/write_support")
+            .context("couldn't check ec_sys write support")?;
+        Ok(enabled.trim() == "Y")
+    }
+
+    /// Initializes a simulated embedded controller, which runs the given
+    /// scenario. This works anywhere, without root. `read_only` works like
+    /// it does for [`Ec::new`].
+    pub fn simulated(cfg: &SimCfg, read_only: bool) -> Result<Self, anyhow::Error> {
+        let sim = sim::Simulator::new(cfg).context("invalid simulation scenario")?;
+        Self::with_backend(Backend::Simulated(Box::new(sim)), read_only)
+    }
+
+    fn with_backend(inner: Backend, read_only: bool) -> Result<Self, anyhow::Error> {
+        // Whatever the fans were doing before we started counts as written
+        // by us, since we can't know any better
+        let mut ec = Self {
+            inner,
+            written_fan_modes: (false, false, false),
+            written_fan_fixed_hw_speeds: (0, 0),
+            read_only,
+            stats: Default::default(),
+        };
+        ec.written_fan_modes = ec.fan_modes().context("couldn't read fan modes")?;
+        ec.written_fan_fixed_hw_speeds = ec
+            .fan_fixed_hw_speeds()
+            .context("couldn't read fixed fan speeds")?;
+        Ok(ec)
     }
 
     /// Sets the file cursor to `offset` bytes from the start of the embedded
@@ -129,8 +222,8 @@ impl Ec {
     /// # Safety
     /// This is *probably* safe, even on invalid hardware. Still, treat it as
     /// if it could brick your computer.
-    unsafe fn set_offset(&mut self, offset: u64) -> EcResult<()> {
-        match self.inner.seek(io::SeekFrom::Start(offset)) {
+    unsafe fn set_offset(file: &mut fs::File, offset: u64) -> EcResult<()> {
+        match file.seek(io::SeekFrom::Start(offset)) {
             Ok(pos) if pos == offset => Ok(()),
             Ok(_) => ec_error!("failed to access EC: seek error"),
             Err(err) => ec_error!("failed to access EC: {}", err),
@@ -143,8 +236,12 @@ impl Ec {
     /// This is *probably* safe, even on invalid hardware. Still, treat it as
     /// if it could brick your computer.
     unsafe fn read_bytes(&mut self, offset: u64, buffer: &mut [u8]) -> EcResult<()> {
-        self.set_offset(offset)?;
-        match self.inner.read(buffer) {
+        let file = match &mut self.inner {
+            Backend::Hardware(file) => file,
+            Backend::Simulated(sim) => return sim.read(offset, buffer),
+        };
+        Self::set_offset(file, offset)?;
+        match file.read(buffer) {
             Ok(num_read) if num_read == buffer.len() => Ok(()),
             Ok(_) => ec_error!("failed to read EC: not enough read"),
             Err(err) => ec_error!("failed to read EC: {}", err),
@@ -185,6 +282,13 @@ impl Ec {
         unsafe { self.read_byte(offs::TEMP_GPU) }
     }
 
+    /// Returns the temperature in degrees Celcius stored at the given EC
+    /// offset. Reading is harmless, but there's no telling what's at offsets
+    /// other than [`TEMP_CPU_OFFSET`] and [`TEMP_GPU_OFFSET`].
+    pub fn temp_at(&mut self, offset: u8) -> EcResult<u8> {
+        unsafe { self.read_byte(offset.into()) }
+    }
+
     /// Returns the RPMs of the left and right fans, respectively.
     pub fn fan_rpm(&mut self) -> EcResult<(u16, u16)> {
         let (mut rpm0, mut rpm1) = ([0u8, 0u8], [0u8, 0u8]);
@@ -223,20 +327,39 @@ impl Ec {
     /// # Safety
     /// This could brick your computer.
     unsafe fn write_bytes(&mut self, offset: u64, buffer: &[u8]) -> EcResult<()> {
-        self.set_offset(offset)?;
-        match self.inner.write(buffer) {
-            Ok(num_read) if num_read == buffer.len() => Ok(()),
+        if self.read_only {
+            eprintln!("[info] read-only, not writing {buffer:02x?} at {offset:#04x}");
+            return Ok(());
+        }
+        let file = match &mut self.inner {
+            Backend::Hardware(file) => file,
+            Backend::Simulated(sim) => {
+                sim.write(offset, buffer)?;
+                self.stats.written += buffer.len() as u64;
+                return Ok(());
+            }
+        };
+        Self::set_offset(file, offset)?;
+        match file.write(buffer) {
+            Ok(num_read) if num_read == buffer.len() => {
+                self.stats.written += buffer.len() as u64;
+                Ok(())
+            }
             Ok(_) => ec_error!("failed to write EC: not enough written"),
             Err(err) => ec_error!("failed to write EC: {}", err),
         }
     }
 
-    /// Write the contents of `buffer` to the given offset in the embedded
-    /// controller.
+    /// Write `byte` to the given offset in the embedded controller, unless
+    /// it's already there.
     ///
     /// # Safety
     /// Same as [`write_bytes`].
     unsafe fn write_byte(&mut self, offset: u64, byte: u8) -> EcResult<()> {
+        if self.read_byte(offset)? == byte {
+            self.stats.skipped += 1;
+            return Ok(());
+        }
         self.write_bytes(offset, std::slice::from_ref(&byte))
     }
 
@@ -252,7 +375,11 @@ impl Ec {
         let byte = self.read_byte(offset)?;
         let shifted = 1u8.checked_shl(bit.into()).expect("invalid bit index");
         let changed = if val { byte | shifted } else { byte & !shifted };
-        self.write_byte(offset, changed)
+        if changed == byte {
+            self.stats.skipped += 1;
+            return Ok(());
+        }
+        self.write_bytes(offset, std::slice::from_ref(&changed))
     }
 
     /// Sets the computer's fan modes.
@@ -263,6 +390,10 @@ impl Ec {
     /// anyways.
     pub fn set_fan_modes(&mut self, (quiet, gaming, fixed): (bool, bool, bool)) -> EcResult<()> {
         assert!(!(quiet && gaming));
+        // Even a partial write is probably what we meant to do
+        if !self.read_only {
+            self.written_fan_modes = (quiet, gaming, fixed);
+        }
         unsafe {
             self.write_bit(offs::FAN_QUIET, quiet)?;
             self.write_bit(offs::FAN_GAMING, gaming)?;
@@ -277,9 +408,33 @@ impl Ec {
     pub fn set_fan_fixed_hw_speeds(&mut self, (fan0, fan1): (u8, u8)) -> EcResult<()> {
         assert!(fan0 <= HW_MAX_FAN_SPEED);
         assert!(fan1 <= HW_MAX_FAN_SPEED);
+        if !self.read_only {
+            self.written_fan_fixed_hw_speeds = (fan0, fan1);
+        }
         unsafe {
             self.write_byte(offs::FAN_FIXED_HW_SPEED_0, fan0)?;
             self.write_byte(offs::FAN_FIXED_HW_SPEED_1, fan1)
         }
     }
+
+    /// Returns how many bytes have been written so far.
+    pub fn write_stats(&self) -> WriteStats {
+        self.stats
+    }
+
+    /// Returns whether writes are only logged.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns the fan modes we last wrote (or found at startup), in the same
+    /// format as [`fan_modes`](Ec::fan_modes).
+    pub fn written_fan_modes(&self) -> (bool, bool, bool) {
+        self.written_fan_modes
+    }
+
+    /// Returns the fixed hardware speeds we last wrote (or found at startup).
+    pub fn written_fan_fixed_hw_speeds(&self) -> (u8, u8) {
+        self.written_fan_fixed_hw_speeds
+    }
 }
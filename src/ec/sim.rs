@@ -0,0 +1,321 @@
+//! A simulated embedded controller, so the server (and everything talking
+//! to it) can be developed without the hardware, or root. Temperatures
+//! follow a script of steps, and the fans respond to the fan mode roughly
+//! like the real ones do.
+use super::*;
+use crate::FanMode;
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Instant;
+
+/// How fast the simulated fans spin at full speed.
+const MAX_RPM: f64 = 5200.0;
+
+/// The right fan is a little slower than the left one, like on my laptop.
+const RIGHT_FAN_FACTOR: f64 = 0.96;
+
+/// The temperatures at which the automatic fan modes idle and max out.
+const AUTO_FAN_TEMPS: (f64, f64) = (45.0, 90.0);
+
+/// A simulation scenario.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimCfg {
+    /// Whether to start over once the last step has happened. The scenario
+    /// then lasts until the last step's `at`.
+    pub repeat: bool,
+
+    /// What happens when, in order. Without any steps, the CPU idles at
+    /// 45°C and the dGPU stays off.
+    pub steps: Vec<SimStepCfg>,
+}
+
+/// A single step of a simulation scenario. Anything left out stays as it
+/// was.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimStepCfg {
+    /// When the step happens, in seconds since the scenario started.
+    pub at: f64,
+
+    /// The CPU temperature to head towards.
+    pub temp_cpu: Option<u8>,
+
+    /// The dGPU temperature to head towards. 0 means the dGPU is off.
+    pub temp_gpu: Option<u8>,
+
+    /// How many seconds it takes to reach the temperatures. By default,
+    /// they're reached immediately.
+    pub ramp: f64,
+
+    /// The fans (0 = left, 1 = right) which are stalled from now on.
+    pub stalled: Option<Vec<u8>>,
+
+    /// Switches to this fan mode as if other software had done it.
+    pub fan_mode: Option<String>,
+
+    /// Sets the raw (quiet, gaming, fixed) fan mode bits as if other
+    /// software had done it, even to combinations which make no sense.
+    pub fan_mode_bits: Option<(bool, bool, bool)>,
+}
+
+impl SimCfg {
+    /// Loads a scenario from the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read scenario {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("couldn't parse scenario {}", path.display()))
+    }
+}
+
+/// A validated [`SimStepCfg`].
+struct Step {
+    at: f64,
+    temps: [Option<u8>; 2],
+    ramp: f64,
+    stalled: Option<[bool; 2]>,
+    fan_mode_bits: Option<(bool, bool, bool)>,
+}
+
+impl Step {
+    fn new(cfg: &SimStepCfg) -> Result<Self, anyhow::Error> {
+        ensure!(
+            cfg.at >= 0.0,
+            "steps can't happen before the scenario starts"
+        );
+        ensure!(cfg.ramp >= 0.0, "ramps can't take negative time");
+        let stalled = match &cfg.stalled {
+            Some(fans) => {
+                let mut stalled = [false; 2];
+                for &fan in fans {
+                    ensure!(fan < 2, "there's no fan {fan}");
+                    stalled[usize::from(fan)] = true;
+                }
+                Some(stalled)
+            }
+            None => None,
+        };
+        let fan_mode_bits = match (&cfg.fan_mode, cfg.fan_mode_bits) {
+            (Some(name), None) => match name.parse() {
+                Ok(FanMode::Quiet) => Some((true, false, false)),
+                Ok(FanMode::Gaming) => Some((false, true, false)),
+                Ok(FanMode::Fixed) => Some((false, false, true)),
+                Ok(_) => Some((false, false, false)),
+                Err(_) => anyhow::bail!("unknown fan mode \"{name}\""),
+            },
+            (None, bits) => bits,
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one of fan_mode and fan_mode_bits can be set")
+            }
+        };
+        Ok(Self {
+            at: cfg.at,
+            temps: [cfg.temp_cpu, cfg.temp_gpu],
+            ramp: cfg.ramp,
+            stalled,
+            fan_mode_bits,
+        })
+    }
+}
+
+/// A linear change in temperature. Times are in seconds since the
+/// simulation started.
+#[derive(Clone, Copy)]
+struct Ramp {
+    from: f64,
+    to: f64,
+    start: f64,
+    duration: f64,
+}
+
+impl Ramp {
+    fn steady(temp: f64) -> Self {
+        Self {
+            from: temp,
+            to: temp,
+            start: 0.0,
+            duration: 0.0,
+        }
+    }
+
+    fn at(&self, time: f64) -> f64 {
+        if time >= self.start + self.duration {
+            return self.to;
+        }
+        let progress = ((time - self.start) / self.duration).max(0.0);
+        self.from + (self.to - self.from) * progress
+    }
+}
+
+/// The simulated embedded controller.
+pub(super) struct Simulator {
+    memory: [u8; 256],
+    started: Instant,
+    steps: Vec<Step>,
+    repeat: bool,
+    /// When the current run through the scenario started, in seconds since
+    /// the simulation started.
+    run_started: f64,
+    /// The index of the next step to happen.
+    next_step: usize,
+    /// The CPU and dGPU temperatures.
+    temps: [Ramp; 2],
+    stalled: [bool; 2],
+}
+
+impl Simulator {
+    pub fn new(cfg: &SimCfg) -> Result<Self, anyhow::Error> {
+        let steps = cfg
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| Step::new(step).with_context(|| format!("invalid step {}", i + 1)))
+            .collect::<Result<Vec<_>, _>>()?;
+        ensure!(
+            steps.windows(2).all(|pair| pair[0].at <= pair[1].at),
+            "the steps have to be in order"
+        );
+        if cfg.repeat {
+            ensure!(
+                steps.last().is_some_and(|step| step.at > 0.0),
+                "a repeating scenario has to last a while"
+            );
+        }
+
+        // The fans start out in normal mode, with the fixed speed at 50%
+        let mut memory = [0; 256];
+        let half_speed = HW_MAX_FAN_SPEED / 2;
+        memory[offs::FAN_FIXED_HW_SPEED_0 as usize] = half_speed;
+        memory[offs::FAN_FIXED_HW_SPEED_1 as usize] = half_speed;
+        let mut sim = Self {
+            memory,
+            started: Instant::now(),
+            steps,
+            repeat: cfg.repeat,
+            run_started: 0.0,
+            next_step: 0,
+            temps: [Ramp::steady(45.0), Ramp::steady(0.0)],
+            stalled: [false; 2],
+        };
+        sim.update();
+        Ok(sim)
+    }
+
+    /// Returns the bytes at `offset`, failing if they're out of range (like
+    /// the real EC's file would).
+    fn range(offset: u64, len: usize) -> EcResult<std::ops::Range<usize>> {
+        match usize::try_from(offset) {
+            Ok(start) if start + len <= 256 => Ok(start..start + len),
+            _ => ec_error!("failed to access simulated EC: offset {offset:#x} out of range"),
+        }
+    }
+
+    pub fn read(&mut self, offset: u64, buffer: &mut [u8]) -> EcResult<()> {
+        self.update();
+        buffer.copy_from_slice(&self.memory[Self::range(offset, buffer.len())?]);
+        Ok(())
+    }
+
+    pub fn write(&mut self, offset: u64, buffer: &[u8]) -> EcResult<()> {
+        self.memory[Self::range(offset, buffer.len())?].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    /// Runs the scenario up to now, and updates the temperatures and fan
+    /// RPMs in memory.
+    fn update(&mut self) {
+        let now = self.started.elapsed().as_secs_f64();
+        loop {
+            match self.steps.get(self.next_step) {
+                Some(step) if self.run_started + step.at <= now => {
+                    self.next_step += 1;
+                    self.apply(self.next_step - 1);
+                }
+                Some(_) => break,
+                None if self.repeat => {
+                    // Checked in new(), so there's always a last step
+                    let length = self.steps.last().map_or(1.0, |step| step.at);
+                    if self.run_started + length > now {
+                        break;
+                    }
+                    self.run_started += length;
+                    self.next_step = 0;
+                }
+                None => break,
+            }
+        }
+
+        let [temp_cpu, temp_gpu] = self.temps.map(|ramp| ramp.at(now).round() as u8);
+        self.memory[offs::TEMP_CPU as usize] = temp_cpu;
+        self.memory[offs::TEMP_GPU as usize] = temp_gpu;
+
+        let speeds = self.fan_speeds(temp_cpu.max(temp_gpu).into());
+        let rpm = |fan: usize, factor: f64| match self.stalled[fan] {
+            true => 0,
+            false => (speeds[fan] * MAX_RPM * factor) as u16,
+        };
+        let (rpm0, rpm1) = (rpm(0, 1.0), rpm(1, RIGHT_FAN_FACTOR));
+        let rpm0_at = offs::FAN_RPM_0 as usize;
+        let rpm1_at = offs::FAN_RPM_1 as usize;
+        self.memory[rpm0_at..rpm0_at + 2].copy_from_slice(&rpm0.to_be_bytes());
+        self.memory[rpm1_at..rpm1_at + 2].copy_from_slice(&rpm1.to_be_bytes());
+    }
+
+    /// Makes the step at `index` happen.
+    fn apply(&mut self, index: usize) {
+        let step = &self.steps[index];
+        let start = self.run_started + step.at;
+        for (ramp, temp) in self.temps.iter_mut().zip(step.temps) {
+            if let Some(temp) = temp {
+                *ramp = Ramp {
+                    from: ramp.at(start),
+                    to: temp.into(),
+                    start,
+                    duration: step.ramp,
+                };
+            }
+        }
+        if let Some(stalled) = step.stalled {
+            self.stalled = stalled;
+        }
+        if let Some((quiet, gaming, fixed)) = step.fan_mode_bits {
+            eprintln!("[info] simulating an external change to the fan mode bits");
+            self.set_bit(offs::FAN_QUIET, quiet);
+            self.set_bit(offs::FAN_GAMING, gaming);
+            self.set_bit(offs::FAN_FIXED, fixed);
+        }
+    }
+
+    fn bit(&self, (offset, bit): (u64, u8)) -> bool {
+        self.memory[offset as usize] & (1 << bit) != 0
+    }
+
+    fn set_bit(&mut self, (offset, bit): (u64, u8), val: bool) {
+        let byte = &mut self.memory[offset as usize];
+        *byte = if val {
+            *byte | 1 << bit
+        } else {
+            *byte & !(1 << bit)
+        };
+    }
+
+    /// Works out how fast (0.0..=1.0) each fan should be spinning, given the
+    /// hottest temperature.
+    fn fan_speeds(&self, hottest: f64) -> [f64; 2] {
+        if self.bit(offs::FAN_FIXED) {
+            let hw_speeds = [offs::FAN_FIXED_HW_SPEED_0, offs::FAN_FIXED_HW_SPEED_1];
+            return hw_speeds.map(|offset| {
+                f64::from(self.memory[offset as usize]) / f64::from(HW_MAX_FAN_SPEED)
+            });
+        }
+        let (idle, max) = AUTO_FAN_TEMPS;
+        let auto = ((hottest - idle) / (max - idle)).clamp(0.0, 1.0);
+        let speed = match (self.bit(offs::FAN_QUIET), self.bit(offs::FAN_GAMING)) {
+            // Quiet mode turns the fans off when it can
+            (true, false) => 0.7 * auto,
+            (false, true) => 0.4 + 0.6 * auto,
+            _ => 0.2 + 0.8 * auto,
+        };
+        [speed; 2]
+    }
+}
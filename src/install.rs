@@ -0,0 +1,259 @@
+//! Installing and uninstalling the server (and the Plasma applet), for
+//! people running it from a checkout and for packagers alike. Everything
+//! goes under a prefix, optionally staged in a `DESTDIR`; without a
+//! `DESTDIR`, the service is also registered and (re)started.
+use anyhow::{ensure, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The unit file we ship. Its `ExecStart` is pointed at the installed binary.
+const UNIT: &str = include_str!("../systemd/a15kb.service");
+
+/// Where the shipped unit expects the binary to be.
+const UNIT_EXEC: &str = "/usr/sbin/a15kb";
+
+/// Lets the bus start the server through systemd.
+const DBUS_SERVICE: &str = "[D-BUS Service]
+Names=com.offbyond.a15kb
+Exec=/bin/false
+User=root
+SystemdService=dbus-com.offbyond.a15kb.service
+";
+
+/// Lets the server own its name, and anyone talk to it.
+const DBUS_POLICY: &str = r#"<?xml version="1.0"?> <!--*-nxml-*-->
+<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-BUS Bus Configuration 1.0//EN"
+	"http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+	<policy user="root">
+		<allow own="com.offbyond.a15kb"/>
+		<allow send_destination="com.offbyond.a15kb"/>
+		<allow receive_sender="com.offbyond.a15kb"/>
+	</policy>
+	<policy user="a15kb">
+		<allow own="com.offbyond.a15kb"/>
+		<allow send_destination="com.offbyond.a15kb"/>
+		<allow receive_sender="com.offbyond.a15kb"/>
+	</policy>
+	<policy context="default">
+		<allow send_destination="com.offbyond.a15kb"/>
+		<allow receive_sender="com.offbyond.a15kb"/>
+	</policy>
+</busconfig>
+"#;
+
+/// Creates the user the server switches to (see `--user`).
+const SYSUSERS: &str = "u a15kb - \"Aero 15 KB hardware control\" -\n";
+
+/// Tells Qt to load the QML plugin for `import com.offbyond.a15kb`.
+const QMLDIR: &str = "module com.offbyond.a15kb\nplugin a15kb_qml_plugin\n";
+
+/// The applet itself, as `(path, contents)`.
+const PLASMOID: &[(&str, &str)] = &[
+    (
+        "metadata.json",
+        include_str!("../plasmoids/a15kb-fans/metadata.json"),
+    ),
+    (
+        "contents/ui/main.qml",
+        include_str!("../plasmoids/a15kb-fans/contents/ui/main.qml"),
+    ),
+    (
+        "contents/ui/Section.qml",
+        include_str!("../plasmoids/a15kb-fans/contents/ui/Section.qml"),
+    ),
+    (
+        "contents/ui/TemperatureDisplay.qml",
+        include_str!("../plasmoids/a15kb-fans/contents/ui/TemperatureDisplay.qml"),
+    ),
+    (
+        "contents/ui/logic.js",
+        include_str!("../plasmoids/a15kb-fans/contents/ui/logic.js"),
+    ),
+];
+
+/// The name of the systemd unit.
+pub const SERVICE: &str = "a15kb.service";
+
+/// Where the old install script used to put the unit. It takes precedence over
+/// ours, so it has to go.
+const LEGACY_UNIT: &str = "/etc/systemd/system/a15kb.service";
+
+/// Where to install things.
+#[derive(Debug, Clone)]
+pub struct InstallCfg {
+    /// The prefix everything is installed under, usually `/usr` or
+    /// `/usr/local`.
+    pub prefix: PathBuf,
+
+    /// If set, files are staged under this directory instead of `/`, and
+    /// nothing is registered or started. For packagers.
+    pub destdir: Option<PathBuf>,
+
+    /// The QML plugin to install along with the applet, usually
+    /// `target/release/examples/liba15kb_qml_plugin.so`. The applet is only
+    /// installed if this is set.
+    pub applet_plugin: Option<PathBuf>,
+}
+
+impl Default for InstallCfg {
+    fn default() -> Self {
+        Self {
+            prefix: "/usr".into(),
+            destdir: std::env::var_os("DESTDIR").map(PathBuf::from),
+            applet_plugin: None,
+        }
+    }
+}
+
+impl InstallCfg {
+    /// Where the server binary goes, as seen once installed.
+    fn binary(&self) -> PathBuf {
+        self.prefix.join("sbin/a15kb")
+    }
+
+    fn unit(&self) -> PathBuf {
+        self.prefix.join("lib/systemd/system").join(SERVICE)
+    }
+
+    fn dbus_service(&self) -> PathBuf {
+        self.prefix
+            .join("share/dbus-1/system-services/com.offbyond.a15kb.service")
+    }
+
+    fn dbus_policy(&self) -> PathBuf {
+        self.prefix
+            .join("share/dbus-1/system.d/com.offbyond.a15kb.conf")
+    }
+
+    fn sysusers(&self) -> PathBuf {
+        self.prefix.join("lib/sysusers.d/a15kb.conf")
+    }
+
+    fn qml_module(&self) -> PathBuf {
+        self.prefix.join("lib/qt/qml/com/offbyond/a15kb")
+    }
+
+    fn plasmoid(&self) -> PathBuf {
+        self.prefix.join("share/plasma/plasmoids/a15kb-fans")
+    }
+
+    /// Returns where `path` actually gets written, taking the `DESTDIR` into
+    /// account.
+    fn staged(&self, path: &Path) -> PathBuf {
+        match &self.destdir {
+            Some(destdir) => destdir.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Writes `contents` to `path`, replacing whatever's there without ever
+    /// leaving a half-written file behind (or failing because the old binary
+    /// is still running).
+    fn write(&self, path: &Path, contents: &[u8], mode: u32) -> Result<(), anyhow::Error> {
+        use std::os::unix::fs::PermissionsExt;
+        let path = self.staged(path);
+        eprintln!("[info] installing {}", path.display());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("couldn't create {}", parent.display()))?;
+        }
+        let temp = path.with_extension("a15kb-new");
+        fs::write(&temp, contents).with_context(|| format!("couldn't write {}", temp.display()))?;
+        fs::set_permissions(&temp, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("couldn't set permissions of {}", temp.display()))?;
+        fs::rename(&temp, &path).with_context(|| format!("couldn't replace {}", path.display()))
+    }
+
+    /// Removes `path`, if it's there.
+    fn remove(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let path = self.staged(path);
+        let result = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path),
+            Ok(_) => fs::remove_file(&path),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => Err(err),
+        };
+        eprintln!("[info] removing {}", path.display());
+        result.with_context(|| format!("couldn't remove {}", path.display()))
+    }
+}
+
+/// Runs `program` with `args`, failing if it does.
+fn run(program: &str, args: &[&str]) -> Result<(), anyhow::Error> {
+    eprintln!("[info] running {program} {}", args.join(" "));
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("couldn't run {program}"))?;
+    ensure!(status.success(), "{program} failed ({status})");
+    Ok(())
+}
+
+/// Installs the server (and the applet, if asked to). Installing over an
+/// existing installation upgrades it.
+pub fn install(cfg: &InstallCfg) -> Result<(), anyhow::Error> {
+    let binary = cfg.binary();
+    let exe = std::env::current_exe().context("couldn't find the a15kb binary")?;
+    let exe = fs::read(&exe).with_context(|| format!("couldn't read {}", exe.display()))?;
+    let unit = UNIT.replace(UNIT_EXEC, binary.to_str().context("invalid prefix")?);
+
+    cfg.write(&binary, &exe, 0o755)?;
+    cfg.write(&cfg.unit(), unit.as_bytes(), 0o644)?;
+    cfg.write(&cfg.dbus_service(), DBUS_SERVICE.as_bytes(), 0o644)?;
+    cfg.write(&cfg.dbus_policy(), DBUS_POLICY.as_bytes(), 0o644)?;
+    cfg.write(&cfg.sysusers(), SYSUSERS.as_bytes(), 0o644)?;
+
+    if let Some(plugin) = &cfg.applet_plugin {
+        let plugin =
+            fs::read(plugin).with_context(|| format!("couldn't read {}", plugin.display()))?;
+        let qml_module = cfg.qml_module();
+        cfg.write(&qml_module.join("liba15kb_qml_plugin.so"), &plugin, 0o755)?;
+        cfg.write(&qml_module.join("qmldir"), QMLDIR.as_bytes(), 0o644)?;
+        let plasmoid = cfg.plasmoid();
+        for (path, contents) in PLASMOID {
+            cfg.write(&plasmoid.join(path), contents.as_bytes(), 0o644)?;
+        }
+    }
+
+    if cfg.destdir.is_some() {
+        // Packagers take it from here
+        return Ok(());
+    }
+    cfg.remove(Path::new(LEGACY_UNIT))?;
+    run("systemd-sysusers", &[])?;
+    run("systemctl", &["daemon-reload"])?;
+    // The bus usually notices the new policy by itself, but not always
+    let _ = run("systemctl", &["reload", "dbus.service"]);
+    run("systemctl", &["enable", SERVICE])?;
+    // Restarting rather than starting, so upgrades take effect
+    run("systemctl", &["restart", SERVICE])?;
+    eprintln!("[info] done, checking the installation");
+    crate::doctor(false)
+}
+
+/// Stops and removes everything [`install`] installed, except the `a15kb`
+/// user and the state directory.
+pub fn uninstall(cfg: &InstallCfg) -> Result<(), anyhow::Error> {
+    if cfg.destdir.is_none() {
+        // It might not be installed (or running) at all
+        let _ = run("systemctl", &["disable", "--now", SERVICE]);
+        cfg.remove(Path::new(LEGACY_UNIT))?;
+    }
+    for path in [
+        cfg.unit(),
+        cfg.dbus_service(),
+        cfg.dbus_policy(),
+        cfg.sysusers(),
+        cfg.binary(),
+        cfg.qml_module(),
+        cfg.plasmoid(),
+    ] {
+        cfg.remove(&path)?;
+    }
+    if cfg.destdir.is_none() {
+        run("systemctl", &["daemon-reload"])?;
+    }
+    Ok(())
+}
@@ -27,27 +27,108 @@
 //! [aeroctl]: https://gitlab.com/wtwrp/aeroctl/
 //! [WinRing0x64.sys]: https://github.com/Soberia/EmbeddedController/blob/main/WinRing0x64.sys
 
+use dbus::arg::{PropMap, RefArg};
 use dbus::blocking::{Connection, Proxy};
+use dbus::message::{MatchRule, SignalArgs};
+use std::cell::Cell;
 use std::fmt::{Display, Formatter};
 use std::ops::RangeInclusive;
-use std::time::Duration;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
+mod doctor;
 mod ec;
+mod install;
 mod server;
 
 #[allow(clippy::type_complexity)]
 #[allow(clippy::needless_borrow)]
+#[allow(clippy::bind_instead_of_map)]
 mod client_generated {
     include! { concat!(env!("OUT_DIR"), "/client_generated.rs") }
 }
+#[allow(clippy::type_complexity)]
+#[allow(clippy::needless_borrow)]
+#[allow(clippy::bind_instead_of_map)]
+#[allow(dead_code)] // members which are the same in v1 are called through v1
+mod client_generated2 {
+    include! { concat!(env!("OUT_DIR"), "/client_generated2.rs") }
+}
 use client_generated::ComOffbyondA15kbController1;
+use client_generated::ComOffbyondA15kbController1ExternalChangeDetected as ExternalChangeDetected;
+use client_generated::ComOffbyondA15kbController1FanStallWarning as FanStallWarning;
+
+/// The v2 trait isn't imported here, since its methods share names with v1's,
+/// so call them like `v2::Controller2::fan_mode(proxy)` instead.
+mod v2 {
+    pub use super::client_generated2::ComOffbyondA15kbController2 as Controller2;
+}
 
+pub use doctor::doctor;
+pub use ec::{SimCfg, SimStepCfg};
+pub use install::{install, uninstall, InstallCfg};
 pub use server::run_server;
+pub use server::ExternalChangePolicy;
+pub use server::GpuCfg;
+pub use server::PowerProfilesCfg;
+pub use server::RateLimitCfg;
+pub use server::RuleCfg;
+pub use server::SandboxCfg;
+pub use server::ScheduleEntryCfg;
+pub use server::SensorCfg;
 pub use server::ServerCfg;
+pub use server::ShutdownPolicy;
+pub use server::DEFAULT_CONFIG_PATH;
+pub use server::DEFAULT_STATE_DIR;
 
-/// The name of the service, which always resides on the system bus.
+/// The name of the service, which normally resides on the system bus.
 pub const BUS_NAME: &str = "com.offbyond.a15kb";
 
+/// A D-Bus bus, which the server can serve on and clients can connect to.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub enum Bus {
+    /// The system bus, where the real server lives.
+    #[default]
+    System,
+    /// The current user's session bus.
+    Session,
+    /// A bus at the given address, e.g. a private `dbus-daemon`'s
+    /// `unix:path=/tmp/a15kb-test.sock`.
+    Address(String),
+}
+
+impl Bus {
+    /// Opens a new connection to the bus.
+    pub fn connect(&self) -> Result<Connection, dbus::Error> {
+        match self {
+            Self::System => Connection::new_system(),
+            Self::Session => Connection::new_session(),
+            Self::Address(address) => {
+                let mut channel = dbus::channel::Channel::open_private(address)?;
+                channel.register()?;
+                Ok(channel.into())
+            }
+        }
+    }
+}
+
+impl FromStr for Bus {
+    type Err = std::convert::Infallible;
+
+    /// Parses `system`, `session`, or anything else as a bus address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "system" => Self::System,
+            "session" => Self::Session,
+            address => Self::Address(address.to_string()),
+        })
+    }
+}
+
+/// The object path of the controller.
+const CONTROLLER_PATH: &str = "/com/offbyond/a15kb/Controller1";
+
 /// Laptop fan mode.
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FanMode {
@@ -61,6 +142,14 @@ pub enum FanMode {
     Gaming,
     /// A fixed, user-controlled fan speed.
     Fixed,
+    /// The fan mode bits are set to a combination which doesn't correspond
+    /// to any fan mode, e.g. because other software wrote to them. Setting
+    /// any other fan mode repairs this.
+    Unknown {
+        quiet: bool,
+        gaming: bool,
+        fixed: bool,
+    },
 }
 
 impl FanMode {
@@ -71,6 +160,10 @@ impl FanMode {
     /// - `1`: [Normal](`self::FanMode#variant.Normal`)
     /// - `2`: [Gaming](`self::FanMode#variant.Gaming`)
     /// - `3`: [Fixed](`self::FanMode#variant.Fixed`)
+    ///
+    /// [Unknown](`self::FanMode#variant.Unknown`) has no discriminant of its
+    /// own; over D-Bus, it's reported as `255` alongside the raw fan mode
+    /// bits.
     pub const fn from_discriminant(discriminant: u8) -> Option<Self> {
         match discriminant {
             0 => Some(Self::Quiet),
@@ -80,45 +173,433 @@ impl FanMode {
             _ => None,
         }
     }
-    /// The inverse of [from_discriminant][`FanMode#method.from_discriminant`]
+    /// The inverse of [from_discriminant][`FanMode#method.from_discriminant`].
+    /// [Unknown](`self::FanMode#variant.Unknown`) fan modes return `255`.
     pub const fn to_discriminant(self) -> u8 {
         match self {
             Self::Quiet => 0,
             Self::Normal => 1,
             Self::Gaming => 2,
             Self::Fixed => 3,
+            Self::Unknown { .. } => u8::MAX,
         }
     }
 }
 
+impl Display for FanMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match *self {
+            Self::Quiet => "quiet",
+            Self::Normal => "normal",
+            Self::Gaming => "gaming",
+            Self::Fixed => "fixed",
+            Self::Unknown {
+                quiet,
+                gaming,
+                fixed,
+            } => {
+                // e.g. "unknown (quiet+gaming)"
+                let bits = [(quiet, "quiet"), (gaming, "gaming"), (fixed, "fixed")];
+                let set: Vec<_> = bits
+                    .into_iter()
+                    .filter_map(|(set, name)| set.then_some(name))
+                    .collect();
+                return write!(f, "unknown ({})", set.join("+"));
+            }
+        };
+        f.write_str(name)
+    }
+}
+
+/// An error thrown when parsing a [`FanMode`] from a string.
+#[derive(Debug)]
+pub struct ParseFanModeError;
+
+impl FromStr for FanMode {
+    type Err = ParseFanModeError;
+    /// Parses the name of a fan mode (as produced by [`Display`]), ignoring
+    /// case. Unknown fan modes can't be parsed.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Quiet, Self::Normal, Self::Gaming, Self::Fixed]
+            .into_iter()
+            .find(|mode| s.eq_ignore_ascii_case(&mode.to_string()))
+            .ok_or(ParseFanModeError {})
+    }
+}
+
 /// The current thermal state of the system.
 #[derive(Default, Debug)]
 pub struct ThermalInfo {
     /// The CPU temperature, in Celcius.
     pub temp_cpu: Celcius,
 
-    /// The GPU temperature, in Celcius. This is 0 if the GPU is currently
-    /// powered off.
+    /// The GPU temperature, in Celcius. This is 0 unless [`Self::gpu`] is
+    /// [`GpuTemp::Temp`].
     pub temp_gpu: Celcius,
 
+    /// Whether the GPU is on, and its temperature if so.
+    pub gpu: GpuTemp,
+
     /// The RPM of the left and right fans, respectively.
     pub fan_rpm: (u16, u16),
 }
 
+/// The state of the dGPU, as reported by the server.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuTemp {
+    /// The dGPU is powered off.
+    Off,
+
+    /// The dGPU is on, at the given temperature.
+    Temp(Celcius),
+
+    /// The dGPU might be on, but the server couldn't read its temperature.
+    #[default]
+    Unknown,
+}
+
+impl GpuTemp {
+    /// Interprets a temperature from the EC, which reports 0 when the dGPU
+    /// is off (and, unfortunately, sometimes when it isn't).
+    fn from_ec(temp: Celcius) -> Self {
+        match temp {
+            0 => Self::Off,
+            temp => Self::Temp(temp),
+        }
+    }
+
+    /// Returns the name of the state, as used by `Controller2`.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Temp(_) => "on",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Returns the temperature, or 0 if there isn't one (like the EC does).
+    fn temp_or_zero(self) -> Celcius {
+        match self {
+            Self::Temp(temp) => temp,
+            Self::Off | Self::Unknown => 0,
+        }
+    }
+}
+
+/// A single reading from one of the server's sensors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensor {
+    /// The sensor's name, e.g. `"cpu"`.
+    pub name: String,
+
+    /// Where the value came from: an EC offset (e.g. `"ec:0x60"`) or a sysfs
+    /// path.
+    pub source: String,
+
+    /// The unit of the value, e.g. `"celsius"` or `"rpm"`. This is empty if
+    /// the server doesn't know.
+    pub unit: String,
+
+    /// The sensor's value, or `None` if the server couldn't read it.
+    pub value: Option<f64>,
+}
+
+/// A per-application rule, as configured on the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// The rule's human-readable name.
+    pub name: String,
+
+    /// Rules with a higher priority win over rules with a lower priority.
+    pub priority: i32,
+
+    /// The executable file name the rule matches, if any.
+    pub exe: Option<String>,
+
+    /// The cgroup substring the rule matches, if any.
+    pub cgroup: Option<String>,
+
+    /// The fan mode the rule switches to, or `None` if the server reported
+    /// an unrecognized fan mode.
+    pub fan_mode: Option<FanMode>,
+
+    /// The fixed fan speed the rule switches to, if any.
+    pub fixed_fan_speed: Option<Percent>,
+}
+
+/// A client's request for a fan state (see [`Client::add_request`]).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanRequest {
+    /// The request's ID, for [`Client::release_request`].
+    pub id: u32,
+
+    /// The unique bus name of the client which made the request.
+    pub owner: String,
+
+    /// The requested fan mode, or `None` if the server reported an
+    /// unrecognized fan mode.
+    pub fan_mode: Option<FanMode>,
+
+    /// The requested fixed fan speed, if the request is for fixed fans.
+    pub fixed_fan_speed: Option<Percent>,
+
+    /// Requests with a higher priority win over requests with a lower
+    /// priority.
+    pub priority: i32,
+
+    /// Why the client made the request.
+    pub reason: String,
+}
+
+/// An upcoming switch point in the server's fan schedule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledChange {
+    /// When the switch will happen.
+    pub at: SystemTime,
+
+    /// The fan mode the server will switch to, or `None` if the server
+    /// reported an unrecognized fan mode.
+    pub fan_mode: Option<FanMode>,
+
+    /// The fixed fan speed the server will switch to, if any.
+    pub fixed_fan_speed: Option<Percent>,
+}
+
+/// The health of a single fan, as judged by a self-test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanHealth {
+    /// The fan kept up with the other fan at every speed.
+    Healthy,
+    /// The fan spun much slower than the other fan at some speed.
+    Weak,
+    /// The fan didn't spin at all at some speed.
+    Stalled,
+}
+
+impl FanHealth {
+    /// Converts a numeric discriminant into its corresponding
+    /// [`FanHealth`]. Returns [`None`] in the case of an unrecognized
+    /// discriminant. The valid discriminants are:
+    /// - `0`: [Healthy](`self::FanHealth#variant.Healthy`)
+    /// - `1`: [Weak](`self::FanHealth#variant.Weak`)
+    /// - `2`: [Stalled](`self::FanHealth#variant.Stalled`)
+    pub const fn from_discriminant(discriminant: u8) -> Option<Self> {
+        match discriminant {
+            0 => Some(Self::Healthy),
+            1 => Some(Self::Weak),
+            2 => Some(Self::Stalled),
+            _ => None,
+        }
+    }
+    /// The inverse of [from_discriminant][`FanHealth#method.from_discriminant`]
+    pub const fn to_discriminant(self) -> u8 {
+        match self {
+            Self::Healthy => 0,
+            Self::Weak => 1,
+            Self::Stalled => 2,
+        }
+    }
+}
+
+/// A single step of a fan self-test.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelfTestStep {
+    /// The fixed fan speed both fans were set to.
+    pub fixed_fan_speed: Percent,
+
+    /// The resulting RPM of the left and right fans, respectively.
+    pub fan_rpm: (u16, u16),
+}
+
+/// The results of a fan self-test.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestResults {
+    /// When the self-test finished.
+    pub finished: SystemTime,
+
+    /// The measurements taken at each step, from slowest to fastest.
+    pub steps: Vec<SelfTestStep>,
+
+    /// The health of the left and right fans, respectively. This is `None`
+    /// if the server reported an unrecognized health.
+    pub health: (Option<FanHealth>, Option<FanHealth>),
+}
+
+/// The state of the fans.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FanState {
+    /// The fan mode.
+    pub fan_mode: FanMode,
+
+    /// The fixed fan speed.
+    pub fixed_fan_speed: Percent,
+}
+
+/// A change to the fans made by something other than a15kb.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExternalChange {
+    /// The fan state a15kb last set.
+    pub before: FanState,
+
+    /// The fan state found in the hardware.
+    pub after: FanState,
+
+    /// Whether the server changed the fans back to `before`.
+    pub reasserted: bool,
+}
+
+/// What the server and hardware support.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// The server's version, or `None` if the server is too old to say.
+    pub server_version: Option<String>,
+
+    /// The laptop's model name (from DMI), or `None` if the server is too
+    /// old to say.
+    pub model: Option<String>,
+
+    /// The fan modes which can be set.
+    pub fan_modes: Vec<FanMode>,
+
+    /// How many fans there are.
+    pub fan_count: u8,
+
+    /// Whether each fan's speed can be set separately.
+    pub per_fan_control: bool,
+
+    /// The names of the temperature sensors, e.g. `"cpu"`.
+    pub sensors: Vec<String>,
+
+    /// The allowable fixed fan speeds.
+    pub fixed_fan_speeds: RangeInclusive<Percent>,
+
+    /// Whether the fans have been calibrated, i.e. whether the RPM-based
+    /// methods work.
+    pub calibrated: bool,
+
+    /// Whether the server is running in read-only mode, where nothing about
+    /// the fans can be changed.
+    pub read_only: bool,
+
+    /// The optional features the server supports, e.g. `"self-test"`.
+    pub features: Vec<String>,
+
+    /// The optional subsystems which are enabled in the server's
+    /// configuration, e.g. `"rules"`.
+    pub subsystems: Vec<String>,
+}
+
+/// Counters kept by the server since it started (see [`Client::stats`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes written to the embedded controller.
+    pub ec_bytes_written: u64,
+
+    /// Bytes not written to the embedded controller because they already
+    /// had the right value.
+    pub ec_bytes_skipped: u64,
+
+    /// Fixed fan speed changes which were replaced by a newer one before the
+    /// server got around to writing them.
+    pub coalesced_writes: u64,
+
+    /// Calls refused because their client made too many too quickly.
+    pub rate_limited_calls: u64,
+}
+
+/// Returns a match rule for the server's `S` signal.
+fn signal_rule<S: SignalArgs>() -> MatchRule<'static> {
+    S::match_rule(Some(&BUS_NAME.into()), Some(&CONTROLLER_PATH.into())).static_clone()
+}
+
 /// Convenience alias.
 type ClientResult<T> = Result<T, dbus::Error>;
 
+/// The newest interface version [`Client`] understands.
+const NEWEST_INTERFACE_VERSION: u32 = 2;
+
+/// Returns whether `err` means the server isn't there (anymore).
+fn server_gone(err: &dbus::Error) -> bool {
+    matches!(
+        err.name(),
+        Some(
+            "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner"
+                | "org.freedesktop.DBus.Error.NoReply"
+                | "org.freedesktop.DBus.Error.Timeout"
+                | "org.freedesktop.DBus.Error.Disconnected"
+        )
+    )
+}
+
+/// Returns the `key` entry of a `Controller2` dictionary, converted by `f`.
+fn dict_entry<T>(
+    dict: &PropMap,
+    key: &str,
+    f: impl FnOnce(&dyn RefArg) -> Option<T>,
+) -> ClientResult<T> {
+    dict.get(key)
+        .and_then(|value| f(&*value.0))
+        .ok_or_else(|| dbus::Error::new_failed(&format!("missing or invalid {key}")))
+}
+
+/// Returns the `key` entry of a `Controller2` dictionary as an integer.
+fn dict_u64(dict: &PropMap, key: &str) -> ClientResult<u64> {
+    dict_entry(dict, key, |value| value.as_u64())
+}
+
+/// Returns the `key` entry of a `Controller2` dictionary as an array of
+/// integers.
+fn dict_u64s(dict: &PropMap, key: &str) -> ClientResult<Vec<u64>> {
+    dict_entry(dict, key, |value| {
+        value.as_iter()?.map(|v| v.as_u64()).collect()
+    })
+}
+
+/// Returns the `key` entry of a `Controller2` dictionary as a string.
+fn dict_string(dict: &PropMap, key: &str) -> ClientResult<String> {
+    dict_entry(dict, key, |value| value.as_str().map(str::to_string))
+}
+
+/// Returns the `key` entry of a `Controller2` dictionary as an array of
+/// strings.
+fn dict_strings(dict: &PropMap, key: &str) -> ClientResult<Vec<String>> {
+    dict_entry(dict, key, |value| {
+        value
+            .as_iter()?
+            .map(|v| v.as_str().map(str::to_string))
+            .collect()
+    })
+}
+
 /// Represents a client connection to the a15kb server.
 /// All method calls are blocking.
 pub struct Client {
     conn: Connection,
+    /// The interface version negotiated with the server, if any.
+    version: Cell<Option<u32>>,
 }
 impl Client {
     /// Creates a new client which lies dormant on the system bus.
     pub fn new() -> ClientResult<Self> {
-        Ok(Self {
-            conn: Connection::new_system()?,
-        })
+        Ok(Self::with_connection(Connection::new_system()?))
+    }
+
+    /// Creates a new client which talks to the server at `address`, e.g. a
+    /// simulated server on a private bus.
+    pub fn with_address(address: &str) -> ClientResult<Self> {
+        Ok(Self::with_connection(
+            Bus::Address(address.to_string()).connect()?,
+        ))
+    }
+
+    /// Creates a new client which talks to the server through an existing
+    /// connection, on whichever bus that is.
+    pub fn with_connection(conn: Connection) -> Self {
+        Self {
+            conn,
+            version: Cell::new(None),
+        }
     }
 
     fn with_proxy<F, T>(&self, mut f: F) -> ClientResult<T>
@@ -126,10 +607,145 @@ impl Client {
         F: FnMut(&Proxy<&'_ Connection>) -> ClientResult<T>,
     {
         const TIMEOUT: Duration = Duration::from_millis(1000);
-        let proxy = self
-            .conn
-            .with_proxy(BUS_NAME, "/com/offbyond/a15kb/Controller1", TIMEOUT);
-        f(&proxy)
+        let proxy = self.conn.with_proxy(BUS_NAME, CONTROLLER_PATH, TIMEOUT);
+        let result = f(&proxy);
+        if result.as_ref().is_err_and(server_gone) {
+            // Whatever server comes back might be a different version
+            self.version.set(None);
+        }
+        result
+    }
+
+    /// Returns the newest interface version supported by both the server and
+    /// this client. The server is only asked the first time (and again after
+    /// it goes away).
+    pub fn interface_version(&self) -> ClientResult<u32> {
+        if let Some(version) = self.version.get() {
+            return Ok(version);
+        }
+        let version = self.with_proxy(|proxy| match v2::Controller2::interface_version(proxy) {
+            Ok(version) => Ok(version.min(NEWEST_INTERFACE_VERSION)),
+            Err(err) if server_gone(&err) => Err(err),
+            // Older servers only have Controller1
+            Err(_) => Ok(1),
+        })?;
+        self.version.set(Some(version));
+        Ok(version)
+    }
+
+    /// Returns what the server and hardware support, so that UIs can hide
+    /// controls which wouldn't work. Servers which only support version 1
+    /// of the interface can't say much, so this fills in what they're known
+    /// to support.
+    pub fn capabilities(&self) -> ClientResult<Capabilities> {
+        if self.interface_version()? < 2 {
+            return Ok(Capabilities {
+                server_version: None,
+                model: None,
+                fan_modes: vec![
+                    FanMode::Quiet,
+                    FanMode::Normal,
+                    FanMode::Gaming,
+                    FanMode::Fixed,
+                ],
+                fan_count: 2,
+                per_fan_control: false,
+                sensors: vec!["cpu".to_string(), "gpu".to_string()],
+                fixed_fan_speeds: self.allowed_fixed_fan_speeds()?,
+                calibrated: false,
+                read_only: false,
+                features: Vec::new(),
+                subsystems: Vec::new(),
+            });
+        }
+        self.with_proxy(|proxy| {
+            let caps = v2::Controller2::get_capabilities(proxy)?;
+            let fan_modes = dict_strings(&caps, "fan_modes")?;
+            let fixed_fan_speed = |key| {
+                let speed = dict_entry(&caps, key, |value| value.as_f64())?;
+                Percent::new(speed).ok_or_else(|| dbus::Error::new_failed("negative fan speed"))
+            };
+            Ok(Capabilities {
+                server_version: Some(dict_string(&caps, "server_version")?),
+                model: Some(dict_string(&caps, "model")?),
+                // Skip any fan modes newer than us
+                fan_modes: fan_modes
+                    .iter()
+                    .filter_map(|name| name.parse().ok())
+                    .collect(),
+                fan_count: dict_u64(&caps, "fan_count")? as u8,
+                per_fan_control: dict_u64(&caps, "per_fan_control")? != 0,
+                sensors: dict_strings(&caps, "sensors")?,
+                fixed_fan_speeds: fixed_fan_speed("fixed_fan_speed_min")?
+                    ..=fixed_fan_speed("fixed_fan_speed_max")?,
+                calibrated: dict_u64(&caps, "calibrated")? != 0,
+                // Older servers don't have a read-only mode
+                read_only: dict_u64(&caps, "read_only").is_ok_and(|read_only| read_only != 0),
+                features: v2::Controller2::capabilities(proxy)?,
+                subsystems: dict_strings(&caps, "subsystems")?,
+            })
+        })
+    }
+
+    /// Waits up to `timeout` for a signal from the server and dispatches it
+    /// to the appropriate handler. Returns `true` if a signal was received.
+    /// Handlers are never called unless this is.
+    pub fn process(&self, timeout: Duration) -> ClientResult<bool> {
+        self.conn.process(timeout)
+    }
+
+    /// Calls `f` whenever the server warns that a fan has stalled. Its
+    /// arguments are the fan (0 = left, 1 = right) and the fixed fan speed
+    /// it should've been spinning at. See [`process`].
+    ///
+    /// [`process`]: Client::process
+    pub fn on_fan_stall_warning<F>(&self, mut f: F) -> ClientResult<()>
+    where
+        F: FnMut(u8, Percent) + Send + 'static,
+    {
+        self.conn.add_match(
+            signal_rule::<FanStallWarning>(),
+            move |warning: FanStallWarning, _, _| {
+                f(warning.fan, Percent(warning.fixed_fan_speed.max(0.0)));
+                true
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Calls `f` whenever the server notices that other software changed the
+    /// fans behind its back. See [`process`].
+    ///
+    /// [`process`]: Client::process
+    pub fn on_external_change<F>(&self, mut f: F) -> ClientResult<()>
+    where
+        F: FnMut(ExternalChange) + Send + 'static,
+    {
+        self.conn.add_match(
+            signal_rule::<ExternalChangeDetected>(),
+            move |detected: ExternalChangeDetected, _, _| {
+                let fan_mode = |discriminant, (quiet, gaming, fixed)| {
+                    FanMode::from_discriminant(discriminant).unwrap_or(FanMode::Unknown {
+                        quiet,
+                        gaming,
+                        fixed,
+                    })
+                };
+                f(ExternalChange {
+                    before: FanState {
+                        fan_mode: fan_mode(detected.before_fan_mode, detected.before_fan_mode_bits),
+                        fixed_fan_speed: Percent(detected.before_fixed_fan_speed.max(0.0)),
+                    },
+                    after: FanState {
+                        fan_mode: fan_mode(detected.after_fan_mode, detected.after_fan_mode_bits),
+                        fixed_fan_speed: Percent(detected.after_fixed_fan_speed.max(0.0)),
+                    },
+                    reasserted: detected.reasserted,
+                });
+                true
+            },
+        )?;
+        Ok(())
     }
 
     /// Returns the server's allowable fan speeds.
@@ -150,6 +766,28 @@ impl Client {
 
     /// Returns the system's current thermal information.
     pub fn thermal_info(&self) -> ClientResult<ThermalInfo> {
+        if self.interface_version()? >= 2 {
+            return self.with_proxy(|proxy| {
+                let info = v2::Controller2::get_thermal_info(proxy)?;
+                let fan_rpm = dict_u64s(&info, "fan_rpm")?;
+                let fan_rpm = match fan_rpm[..] {
+                    [rpm0, rpm1] => (rpm0 as u16, rpm1 as u16),
+                    _ => return Err(dbus::Error::new_failed("wrong number of fans")),
+                };
+                let temp_gpu = dict_u64(&info, "temp_gpu")? as Celcius;
+                let gpu = match dict_string(&info, "gpu")?.as_str() {
+                    "off" => GpuTemp::Off,
+                    "on" => GpuTemp::Temp(temp_gpu),
+                    _ => GpuTemp::Unknown,
+                };
+                Ok(ThermalInfo {
+                    temp_cpu: dict_u64(&info, "temp_cpu")? as Celcius,
+                    temp_gpu,
+                    gpu,
+                    fan_rpm,
+                })
+            });
+        }
         self.with_proxy(|proxy| {
             let (temp_cpu, temp_gpu, fan_rpm) = proxy.get_thermal_info()?;
             // let fixed_fan_speed = Percent::try_from(fixed_fan_speed)
@@ -158,18 +796,76 @@ impl Client {
             Ok(ThermalInfo {
                 temp_cpu,
                 temp_gpu,
+                gpu: GpuTemp::from_ec(temp_gpu),
                 fan_rpm,
             })
         })
     }
 
-    /// Returns the current fan mode, or `None` if the fan mode is unrecognized.
-    pub fn fan_mode(&self) -> ClientResult<Option<FanMode>> {
-        self.with_proxy(|proxy| Ok(FanMode::from_discriminant(proxy.fan_mode()?)))
+    /// Reads every sensor the server knows about. Servers which only support
+    /// version 1 of the interface only know about the CPU and GPU
+    /// temperatures.
+    pub fn sensors(&self) -> ClientResult<Vec<Sensor>> {
+        if self.interface_version()? < 2 {
+            let info = self.thermal_info()?;
+            let sensor = |name: &str, source: &str, temp: Celcius| Sensor {
+                name: name.to_string(),
+                source: source.to_string(),
+                unit: "celsius".to_string(),
+                value: Some(f64::from(temp)),
+            };
+            return Ok(vec![
+                sensor("cpu", "ec:0x60", info.temp_cpu),
+                sensor("gpu", "ec:0x61", info.temp_gpu),
+            ]);
+        }
+        self.with_proxy(|proxy| {
+            let sensors = v2::Controller2::get_sensors(proxy)?;
+            let sensors = sensors
+                .into_iter()
+                .map(|(name, source, unit, value)| Sensor {
+                    name,
+                    source,
+                    unit,
+                    value: (!value.is_nan()).then_some(value),
+                });
+            Ok(sensors.collect())
+        })
+    }
+
+    /// Returns the current fan mode. If the fan mode bits are in an invalid
+    /// state, this returns [`FanMode::Unknown`] with the raw bits.
+    pub fn fan_mode(&self) -> ClientResult<FanMode> {
+        let known = if self.interface_version()? >= 2 {
+            self.with_proxy(|proxy| Ok(v2::Controller2::fan_mode(proxy)?.parse().ok()))?
+        } else {
+            self.with_proxy(|proxy| Ok(FanMode::from_discriminant(proxy.fan_mode()?)))?
+        };
+        self.with_proxy(|proxy| match known {
+            Some(fan_mode) => Ok(fan_mode),
+            None => {
+                let (quiet, gaming, fixed) = proxy.raw_fan_mode_bits()?;
+                Ok(FanMode::Unknown {
+                    quiet,
+                    gaming,
+                    fixed,
+                })
+            }
+        })
+    }
+
+    /// Returns the raw `(quiet, gaming, fixed)` fan mode bits. Normally at
+    /// most one of these is set.
+    pub fn raw_fan_mode_bits(&self) -> ClientResult<(bool, bool, bool)> {
+        self.with_proxy(|proxy| proxy.raw_fan_mode_bits())
     }
 
     /// Attempts to set the current fan mode.
     pub fn set_fan_mode(&self, fan_mode: FanMode) -> ClientResult<()> {
+        if self.interface_version()? >= 2 {
+            return self
+                .with_proxy(|proxy| v2::Controller2::set_fan_mode(proxy, fan_mode.to_string()));
+        }
         self.with_proxy(|proxy| proxy.set_fan_mode(fan_mode.to_discriminant()))
     }
 
@@ -189,6 +885,237 @@ impl Client {
     pub fn set_fixed_fan_speed(&self, fixed_fan_speed: Percent) -> ClientResult<()> {
         self.with_proxy(|proxy| proxy.set_fixed_fan_speed(fixed_fan_speed.as_f64()))
     }
+
+    /// Switches to `fan_mode` for the given duration (rounded down to whole
+    /// seconds), after which the server restores the previous fan mode and
+    /// fixed fan speed. Calling [`set_fan_mode`] cancels the override.
+    ///
+    /// [`set_fan_mode`]: Client::set_fan_mode
+    pub fn set_fan_mode_for(&self, fan_mode: FanMode, duration: Duration) -> ClientResult<()> {
+        let seconds = u32::try_from(duration.as_secs()).unwrap_or(u32::MAX);
+        if self.interface_version()? >= 2 {
+            return self.with_proxy(|proxy| {
+                v2::Controller2::set_fan_mode_for(proxy, &fan_mode.to_string(), seconds)
+            });
+        }
+        self.with_proxy(|proxy| proxy.set_fan_mode_for(fan_mode.to_discriminant(), seconds))
+    }
+
+    /// Returns how long the override started by [`set_fan_mode_for`] has
+    /// left, or `None` if there isn't one.
+    ///
+    /// [`set_fan_mode_for`]: Client::set_fan_mode_for
+    pub fn override_remaining(&self) -> ClientResult<Option<Duration>> {
+        self.with_proxy(|proxy| {
+            let seconds = proxy.override_remaining()?;
+            Ok((seconds != 0).then(|| Duration::from_secs(seconds.into())))
+        })
+    }
+
+    /// Returns the next switch point in the server's fan schedule, or `None`
+    /// if nothing is scheduled.
+    pub fn next_scheduled_change(&self) -> ClientResult<Option<ScheduledChange>> {
+        self.with_proxy(|proxy| {
+            let (unix_time, fan_mode, fixed_fan_speed) = proxy.next_scheduled_change()?;
+            let Ok(unix_time @ 1..) = u64::try_from(unix_time) else {
+                return Ok(None);
+            };
+            Ok(Some(ScheduledChange {
+                at: SystemTime::UNIX_EPOCH + Duration::from_secs(unix_time),
+                fan_mode: FanMode::from_discriminant(fan_mode),
+                fixed_fan_speed: Percent::new(fixed_fan_speed),
+            }))
+        })
+    }
+
+    /// Starts a fan self-test on the server. This returns immediately; the
+    /// self-test itself takes about half a minute. While it's running, the
+    /// fan state can't be changed.
+    pub fn start_self_test(&self) -> ClientResult<()> {
+        self.with_proxy(|proxy| proxy.start_self_test())
+    }
+
+    /// Returns whether a fan self-test is currently running.
+    pub fn self_test_running(&self) -> ClientResult<bool> {
+        self.with_proxy(|proxy| proxy.self_test_running())
+    }
+
+    /// Returns the results of the last finished fan self-test, or `None` if
+    /// no self-test has finished yet.
+    pub fn self_test_results(&self) -> ClientResult<Option<SelfTestResults>> {
+        self.with_proxy(|proxy| {
+            let (finished, steps, (health0, health1)) = proxy.get_self_test_results()?;
+            let Ok(finished @ 1..) = u64::try_from(finished) else {
+                return Ok(None);
+            };
+            let steps = steps
+                .into_iter()
+                .map(|(fixed_fan_speed, rpm0, rpm1)| {
+                    let fixed_fan_speed = Percent::try_from(fixed_fan_speed)
+                        .map_err(|_| dbus::Error::new_failed("negative fan speed"))?;
+                    Ok(SelfTestStep {
+                        fixed_fan_speed,
+                        fan_rpm: (rpm0, rpm1),
+                    })
+                })
+                .collect::<ClientResult<_>>()?;
+            Ok(Some(SelfTestResults {
+                finished: SystemTime::UNIX_EPOCH + Duration::from_secs(finished),
+                steps,
+                health: (
+                    FanHealth::from_discriminant(health0),
+                    FanHealth::from_discriminant(health1),
+                ),
+            }))
+        })
+    }
+
+    /// Starts calibrating the fans' RPMs on the server. This returns
+    /// immediately; calibration itself takes about a minute. Once it's done,
+    /// the RPM-based methods below start working.
+    pub fn start_calibration(&self) -> ClientResult<()> {
+        self.with_proxy(|proxy| proxy.start_calibration())
+    }
+
+    /// Returns whether the fans are currently being calibrated.
+    pub fn calibrating(&self) -> ClientResult<bool> {
+        self.with_proxy(|proxy| proxy.calibrating())
+    }
+
+    /// Returns the approximate RPM of the left and right fans at the current
+    /// fixed fan speed. Fails if the fans haven't been calibrated.
+    pub fn fixed_fan_rpm(&self) -> ClientResult<(u16, u16)> {
+        self.with_proxy(|proxy| proxy.fixed_fan_rpm())
+    }
+
+    /// Sets the fixed fan speed so that both fans spin at approximately
+    /// `rpm`. The RPM should be in the range returned by
+    /// [`allowed_fixed_fan_rpms`].
+    ///
+    /// [`allowed_fixed_fan_rpms`]: Client::allowed_fixed_fan_rpms
+    pub fn set_target_fan_rpm(&self, rpm: u16) -> ClientResult<()> {
+        self.with_proxy(|proxy| proxy.set_target_fan_rpm(rpm))
+    }
+
+    /// Returns the server's allowable fan speeds in RPM. Fails if the fans
+    /// haven't been calibrated.
+    pub fn allowed_fixed_fan_rpms(&self) -> ClientResult<RangeInclusive<u16>> {
+        self.with_proxy(|proxy| {
+            let (min, max) = proxy.allowed_fixed_fan_rpms()?;
+            if min > max {
+                Err(dbus::Error::new_failed("reversed RPM range"))
+            } else {
+                Ok(min..=max)
+            }
+        })
+    }
+
+    /// Returns the server's per-application rules, highest priority first.
+    pub fn rules(&self) -> ClientResult<Vec<Rule>> {
+        self.with_proxy(|proxy| {
+            let non_empty = |s: String| (!s.is_empty()).then_some(s);
+            let rules = proxy.get_rules()?.into_iter().map(
+                |(name, priority, exe, cgroup, fan_mode, fixed_fan_speed)| Rule {
+                    name,
+                    priority,
+                    exe: non_empty(exe),
+                    cgroup: non_empty(cgroup),
+                    fan_mode: FanMode::from_discriminant(fan_mode),
+                    fixed_fan_speed: Percent::new(fixed_fan_speed),
+                },
+            );
+            Ok(rules.collect())
+        })
+    }
+
+    /// Returns the name of the per-application rule currently in effect, if
+    /// any.
+    pub fn active_rule(&self) -> ClientResult<Option<String>> {
+        self.with_proxy(|proxy| {
+            let name = proxy.active_rule()?;
+            Ok((!name.is_empty()).then_some(name))
+        })
+    }
+
+    /// Asks the server to put the fans in the given state for as long as
+    /// this client is connected, returning the request's ID. The request
+    /// with the highest priority wins; while any request is in effect,
+    /// setting the fans directly fails. `fixed_fan_speed` is only used with
+    /// [`FanMode::Fixed`]. This needs a server with the `requests` feature.
+    pub fn add_request(
+        &self,
+        fan_mode: FanMode,
+        fixed_fan_speed: Option<Percent>,
+        priority: i32,
+        reason: &str,
+    ) -> ClientResult<u32> {
+        let fixed_fan_speed = fixed_fan_speed.map_or(0.0, Percent::as_f64);
+        self.with_proxy(|proxy| {
+            v2::Controller2::add_request(
+                proxy,
+                &fan_mode.to_string(),
+                fixed_fan_speed,
+                priority,
+                reason,
+            )
+        })
+    }
+
+    /// Releases a request made by [`add_request`]. Requests are also
+    /// released when the client which made them disconnects.
+    ///
+    /// [`add_request`]: Client::add_request
+    pub fn release_request(&self, id: u32) -> ClientResult<()> {
+        self.with_proxy(|proxy| v2::Controller2::release_request(proxy, id))
+    }
+
+    /// Pins the fans in their current state until the returned file
+    /// descriptor is dropped (along with any copies of it). In the meantime,
+    /// nothing can change them except the server's thermal safety override.
+    /// This needs a server with the `inhibit` feature.
+    pub fn inhibit(&self, reason: &str) -> ClientResult<OwnedFd> {
+        let fd = self.with_proxy(|proxy| v2::Controller2::inhibit(proxy, reason))?;
+        // SAFETY: the fd was just taken out of an OwnedFd, so it's open and
+        // has no other owner
+        Ok(unsafe { OwnedFd::from_raw_fd(fd.into_fd()) })
+    }
+
+    /// Returns the server's write and rate limiting counters. This needs a
+    /// server with the `stats` feature.
+    pub fn stats(&self) -> ClientResult<Stats> {
+        self.with_proxy(|proxy| {
+            let stats = v2::Controller2::get_stats(proxy)?;
+            Ok(Stats {
+                ec_bytes_written: dict_u64(&stats, "ec_bytes_written")?,
+                ec_bytes_skipped: dict_u64(&stats, "ec_bytes_skipped")?,
+                coalesced_writes: dict_u64(&stats, "coalesced_writes")?,
+                rate_limited_calls: dict_u64(&stats, "rate_limited_calls")?,
+            })
+        })
+    }
+
+    /// Returns every client's active requests, starting with the one in
+    /// effect.
+    pub fn requests(&self) -> ClientResult<Vec<FanRequest>> {
+        self.with_proxy(|proxy| {
+            let requests = v2::Controller2::get_requests(proxy)?.into_iter().map(
+                |(id, owner, fan_mode, fixed_fan_speed, priority, reason)| {
+                    let fan_mode = fan_mode.parse().ok();
+                    FanRequest {
+                        id,
+                        owner,
+                        fan_mode,
+                        fixed_fan_speed: (fan_mode == Some(FanMode::Fixed))
+                            .then(|| Percent::new(fixed_fan_speed))
+                            .flatten(),
+                        priority,
+                        reason,
+                    }
+                },
+            );
+            Ok(requests.collect())
+        })
+    }
 }
 
 /// A temperature in degrees Celcius.
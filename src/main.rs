@@ -1,16 +1,102 @@
 #![cfg(target_os = "linux")]
-use anyhow::{bail, Error};
+use anyhow::{bail, ensure, Context, Error};
+use std::path::PathBuf;
 
-/// Runs the server.
-/// Accepted args:
+/// Runs the server, or one of these subcommands:
+/// - `install`: Installs the server and registers it with systemd.
+/// - `uninstall`: Undoes `install`.
+/// - `doctor [--json]`: Checks whether the server can run, and whether it
+///   is, with hints on fixing what's wrong. `--json` is for bug reports.
+///
+/// `install` and `uninstall` accept:
+/// - `--prefix <path>`: Installs under `path` instead of `/usr`.
+/// - `--destdir <path>`: Stages the files under `path` instead of `/`, and
+///   doesn't touch the running system. Defaults to `$DESTDIR`.
+/// - `--applet <plugin>`: Also installs the Plasma applet, using the QML
+///   plugin at `plugin` (build it with
+///   `cargo build --release --example a15kb-qml-plugin`).
+///
+/// The server accepts:
 /// - `--replace`: Replaces the running a15kb server. (Untested)
+/// - `--config <path>`: Reads the configuration from `path` instead of
+///   [`a15kb::DEFAULT_CONFIG_PATH`].
+/// - `--bus <bus>`: Serves on `system` (the default), `session`, or the bus
+///   at the given address.
+/// - `--simulate`: Uses a simulated embedded controller instead of the real
+///   one, so the server can run anywhere, without root.
+/// - `--scenario <path>`: Like `--simulate`, but runs the simulation scenario
+///   in `path`.
+/// - `--read-only`: Never writes to the embedded controller, for trying the
+///   server out on other models. Anything that would change the fans fails,
+///   and what would have been written is logged instead.
+/// - `--user <name>`: Switches to the user `name` once the embedded
+///   controller is open. The server has to be started as root for this.
+/// - `--seccomp`: Forbids system calls the server never needs once the
+///   embedded controller is open.
 pub fn main() -> Result<(), Error> {
-    let mut args = std::env::args();
-    let replace = match args.nth(1).as_deref() {
-        Some("--replace") => true,
-        Some(_) => bail!("unknown argument"),
-        None => false,
+    let mut replace = false;
+    let mut config_path = PathBuf::from(a15kb::DEFAULT_CONFIG_PATH);
+    let mut bus = a15kb::Bus::System;
+    let mut simulation = None;
+    let mut sandbox = a15kb::SandboxCfg::default();
+    let mut read_only = false;
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("install") => return a15kb::install(&install_cfg(args.skip(1))?),
+        Some("uninstall") => return a15kb::uninstall(&install_cfg(args.skip(1))?),
+        Some("doctor") => {
+            let json = match args.nth(1).as_deref() {
+                None => false,
+                Some("--json") => true,
+                Some(_) => bail!("unknown argument"),
+            };
+            ensure!(args.next().is_none(), "unknown argument");
+            return a15kb::doctor(json);
+        }
+        _ => {}
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--replace" => replace = true,
+            "--config" => config_path = args.next().context("missing config path")?.into(),
+            "--bus" => bus = args.next().context("missing bus")?.parse()?,
+            "--simulate" => {
+                simulation.get_or_insert_with(a15kb::SimCfg::default);
+            }
+            "--scenario" => {
+                let path = PathBuf::from(args.next().context("missing scenario path")?);
+                simulation = Some(a15kb::SimCfg::load(&path)?);
+            }
+            "--user" => sandbox.user = Some(args.next().context("missing user")?),
+            "--seccomp" => sandbox.seccomp = true,
+            "--read-only" => read_only = true,
+            _ => bail!("unknown argument"),
+        }
+    }
+    let cfg = a15kb::ServerCfg {
+        replace,
+        bus,
+        simulation,
+        sandbox,
+        read_only,
+        ..a15kb::ServerCfg::load(&config_path)?
     };
-    let cfg = a15kb::ServerCfg { replace };
     a15kb::run_server(&cfg)
 }
+
+/// Parses the arguments of `install` and `uninstall`.
+fn install_cfg(mut args: impl Iterator<Item = String>) -> Result<a15kb::InstallCfg, Error> {
+    let mut cfg = a15kb::InstallCfg::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => cfg.prefix = args.next().context("missing prefix")?.into(),
+            "--destdir" => cfg.destdir = Some(args.next().context("missing destdir")?.into()),
+            "--applet" => {
+                cfg.applet_plugin = Some(args.next().context("missing QML plugin")?.into());
+            }
+            _ => bail!("unknown argument"),
+        }
+    }
+    ensure!(cfg.prefix.is_absolute(), "the prefix has to be absolute");
+    Ok(cfg)
+}
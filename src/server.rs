@@ -1,106 +1,973 @@
 use super::*;
 use anyhow::Context;
 use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, SignalArgs};
 use dbus_crossroads::Crossroads;
-use std::cell::RefCell;
+use serde::{Deserialize, Deserializer};
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+mod calibration;
+mod diagnostics;
+mod gpu;
+mod inhibit;
+mod power_profiles;
+mod requests;
+mod rules;
+mod sandbox;
+mod schedule;
+mod sensors;
+mod shutdown;
+mod systemd;
+mod tamper;
+mod throttle;
+mod v2;
+
+pub use gpu::GpuCfg;
+pub use power_profiles::PowerProfilesCfg;
+pub use rules::RuleCfg;
+pub use sandbox::SandboxCfg;
+pub use schedule::ScheduleEntryCfg;
+pub use sensors::SensorCfg;
+pub use shutdown::ShutdownPolicy;
+pub use tamper::ExternalChangePolicy;
+pub use throttle::RateLimitCfg;
 
 #[allow(clippy::type_complexity)]
 mod server_generated {
     include! { concat!(env!("OUT_DIR"), "/server_generated.rs") }
 }
 
+/// Where the server looks for its configuration file by default.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/a15kb.toml";
+
+/// Where the server keeps its state (e.g. the calibration table) by default.
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/a15kb";
+
+/// How long to wait for incoming messages before running periodic tasks.
+const TICK: Duration = Duration::from_millis(250);
+
+/// How often to check up on the hardware.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 /// The configuration for the a15kb server.
-#[derive(Debug, Default)]
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ServerCfg {
     /// Whether to replace the existing service, if one exists.
+    #[serde(skip)]
     pub replace: bool,
+
+    /// Which bus to serve on.
+    #[serde(skip)]
+    pub bus: Bus,
+
+    /// If set, a simulated embedded controller running this scenario is
+    /// used instead of the real one.
+    #[serde(skip)]
+    pub simulation: Option<ec::SimCfg>,
+
+    /// How to give up privileges once the embedded controller is open.
+    #[serde(skip)]
+    pub sandbox: SandboxCfg,
+
+    /// If set, nothing is ever written to the embedded controller. Clients
+    /// can read everything, but can't change anything.
+    #[serde(skip)]
+    pub read_only: bool,
+
+    /// Integration with `power-profiles-daemon`.
+    pub power_profiles: PowerProfilesCfg,
+
+    /// Per-application fan rules.
+    pub rules: Vec<RuleCfg>,
+
+    /// Time-of-day fan schedule.
+    pub schedule: Vec<ScheduleEntryCfg>,
+
+    /// Where to keep state which should survive restarts.
+    pub state_dir: PathBuf,
+
+    /// What to do when other software changes the fans behind our back.
+    pub external_changes: ExternalChangePolicy,
+
+    /// What to do with the fans when the server stops.
+    pub on_shutdown: ShutdownPolicy,
+
+    /// Extra sensors, on top of the CPU and GPU temperatures.
+    pub sensors: Vec<SensorCfg>,
+
+    /// Cross-checking the dGPU's temperature.
+    pub gpu: GpuCfg,
+
+    /// If the CPU or dGPU gets this hot while an inhibitor lock pins the
+    /// fans, switch to gaming fans anyway until it cools down.
+    pub safety_temp: Celcius,
+
+    /// How many calls which change the fans each client can make.
+    pub rate_limit: RateLimitCfg,
+}
+
+impl Default for ServerCfg {
+    fn default() -> Self {
+        Self {
+            replace: false,
+            bus: Bus::System,
+            simulation: None,
+            sandbox: Default::default(),
+            read_only: false,
+            power_profiles: Default::default(),
+            rules: Vec::new(),
+            schedule: Vec::new(),
+            state_dir: DEFAULT_STATE_DIR.into(),
+            external_changes: Default::default(),
+            on_shutdown: Default::default(),
+            sensors: Vec::new(),
+            gpu: Default::default(),
+            safety_temp: inhibit::DEFAULT_SAFETY_TEMP,
+            rate_limit: Default::default(),
+        }
+    }
+}
+
+impl ServerCfg {
+    /// Loads the configuration from the TOML file at `path`. A missing file
+    /// is treated as an empty configuration, since every setting is
+    /// optional.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("couldn't parse config file {}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => {
+                Err(err).with_context(|| format!("couldn't read config file {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Deserializes a [`FanMode`] from its name.
+fn de_fan_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FanMode, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    name.parse()
+        .map_err(|_| serde::de::Error::custom(format!("unknown fan mode \"{name}\"")))
+}
+
+/// Converts raw `(quiet, gaming, fixed)` fan mode bits into a fan mode.
+fn decode_fan_modes(fan_modes: (bool, bool, bool)) -> FanMode {
+    match fan_modes {
+        (false, false, false) => FanMode::Normal,
+        (true, false, false) => FanMode::Quiet,
+        (false, true, false) => FanMode::Gaming,
+        // quiet AND gaming?
+        (quiet, gaming, fixed @ false) => FanMode::Unknown {
+            quiet,
+            gaming,
+            fixed,
+        },
+        (_, _, true) => FanMode::Fixed,
+    }
+}
+
+/// Converts a fan mode into raw `(quiet, gaming, fixed)` fan mode bits.
+///
+/// # Panics
+/// Panics if `fan_mode` is [`FanMode::Unknown`], since the only unknown fan
+/// mode is one we refuse to write.
+fn fan_mode_bits(fan_mode: FanMode) -> (bool, bool, bool) {
+    match fan_mode {
+        FanMode::Quiet => (true, false, false),
+        FanMode::Normal => (false, false, false),
+        FanMode::Gaming => (false, true, false),
+        FanMode::Fixed => (false, false, true),
+        FanMode::Unknown { .. } => panic!("can't write an unknown fan mode"),
+    }
+}
+
+/// Converts the hardware speeds of both fans into a single fixed fan speed
+/// (0.0..=1.0).
+fn decode_fixed_hw_speeds((hw0, hw1): (u8, u8)) -> f64 {
+    // TODO: Maybe expose each fan's speed individually?
+    let fl0 = (hw0 as f64) / (ec::HW_MAX_FAN_SPEED as f64);
+    let fl1 = (hw1 as f64) / (ec::HW_MAX_FAN_SPEED as f64);
+    0.5 * (fl0 + fl1)
+}
+
+/// Converts a fixed fan speed (0.0..=1.0) into a hardware fan speed.
+fn fixed_hw_speed(fixed_fan_speed: f64) -> u8 {
+    let fhw_speed = fixed_fan_speed * (ec::HW_MAX_FAN_SPEED as f64);
+    fhw_speed as u8
+}
+
+/// Returns the error for attempting to change the fans during a self-test or
+/// calibration.
+fn busy_error() -> dbus::MethodErr {
+    (
+        "com.offbyond.a15kb.Error.Busy",
+        "the fans are being tested or calibrated",
+    )
+        .into()
+}
+
+/// Returns the error for attempting to change the fans in read-only mode.
+fn read_only_error() -> dbus::MethodErr {
+    (
+        "com.offbyond.a15kb.Error.ReadOnly",
+        "the server is running in read-only mode",
+    )
+        .into()
 }
 
-/// Runs the a15kb server with the configuration given by `cfg`.
+/// Returns the error for RPM-based requests before the fans are calibrated.
+fn not_calibrated_error() -> dbus::MethodErr {
+    (
+        "com.offbyond.a15kb.Error.NotCalibrated",
+        "the fans haven't been calibrated",
+    )
+        .into()
+}
+
+/// Runs the a15kb server with the configuration given by `cfg`, until it
+/// gets SIGTERM or SIGINT.
 pub fn run_server(cfg: &ServerCfg) -> Result<(), anyhow::Error> {
     // Set up our controller
-    let controller = Controller::new()?;
+    let controller = Controller::new(cfg)?;
+    let stop = shutdown::StopFlag::install()?;
+    // Everything which needs root is done, so give it up before talking to
+    // anyone
+    sandbox::apply(&cfg.sandbox, &cfg.state_dir)?;
 
-    // Connect to the system bus & grab the name
+    // Connect to the bus & grab the name
     // If we can't grab it, just error out, don't stall in the queue
-    let cxn = Connection::new_system().context("couldn't connect to system bus")?;
+    let cxn = cfg.bus.connect().context("couldn't connect to bus")?;
     cxn.request_name(BUS_NAME, true, cfg.replace, true)
         .context("couldn't obtain bus name")?;
 
     // Set up our D-Bus object
     let mut cr = Crossroads::new();
-    let token = server_generated::register_com_offbyond_a15kb_controller1(&mut cr);
-    cr.insert("/com/offbyond/a15kb/Controller1", &[token], controller);
+    let token1 = server_generated::register_com_offbyond_a15kb_controller1(&mut cr);
+    let token2 = v2::server_generated2::register_com_offbyond_a15kb_controller2(&mut cr);
+    cr.insert(CONTROLLER_PATH, &[token1, token2], controller);
+
+    // This is basically `Crossroads::serve`, except we need to keep a handle
+    // to the controller so we can run periodic tasks & react to signals.
+    let cr = Arc::new(Mutex::new(cr));
+    let cr_recv = Arc::clone(&cr);
+    cxn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, cxn| {
+            let mut cr = cr_recv.lock().unwrap();
+            // Requests need to know who made them
+            if let Some(controller) = cr.data_mut::<Controller>(&CONTROLLER_PATH.into()) {
+                controller.caller = msg.sender().map(|sender| sender.to_string());
+            }
+            cr.handle_message(msg, cxn).unwrap();
+            true
+        }),
+    );
+    requests::watch(&cxn, &cr).context("couldn't watch for clients leaving")?;
+    if cfg.power_profiles.follow {
+        if let Err(err) = power_profiles::watch(&cxn, &cr) {
+            eprintln!("[warn] couldn't watch power profiles: {err}");
+        }
+    }
 
     // Let's go!
     eprintln!("[info] server started");
-    cr.serve(&cxn)?;
-    eprintln!("[info] server stopped");
-    Ok(())
+    systemd::notify("READY=1");
+    while !stop.is_set() {
+        cxn.process(TICK)?;
+        with_controller(&cr, |controller| controller.tick(&cxn));
+    }
+
+    eprintln!("[info] stopping server");
+    systemd::notify("STOPPING=1");
+    // Give up the name first, so nobody changes the fans while we're at it
+    let _ = cxn.release_name(BUS_NAME);
+    with_controller(&cr, |controller| controller.shut_down(cfg.on_shutdown))
+        .context("couldn't leave the fans as configured")
+}
+
+/// Runs `f` on the controller owned by `cr`.
+fn with_controller<T>(cr: &Mutex<Crossroads>, f: impl FnOnce(&mut Controller) -> T) -> T {
+    let mut cr = cr.lock().unwrap();
+    let controller = cr
+        .data_mut(&CONTROLLER_PATH.into())
+        .expect("controller is missing");
+    f(controller)
+}
+
+/// The fan mode and fixed fan speeds, saved before an automatic change so
+/// that they can be restored afterwards.
+#[derive(Debug, Clone, Copy)]
+struct FanSnapshot {
+    fan_mode: FanMode,
+    fixed_hw_speeds: (u8, u8),
+}
+
+/// A temporary fan mode, started by `SetFanModeFor`.
+#[derive(Debug, Clone, Copy)]
+struct FanOverride {
+    /// When to restore the previous fan state.
+    until: Instant,
+    /// The fan state from before the override took effect.
+    previous: FanSnapshot,
 }
 
 /// A D-Bus compatible, high-level wrapper around the raw embedded controller
 struct Controller {
     ec: RefCell<ec::Ec>,
+    power_profiles: PowerProfilesCfg,
+    /// A power profile which should be published on the next tick.
+    pending_profile: Cell<Option<&'static str>>,
+    rules: rules::RuleEngine,
+    /// The fan state from before the active rule took effect.
+    pre_rule: Option<FanSnapshot>,
+    fan_override: Cell<Option<FanOverride>>,
+    schedule: schedule::Schedule,
+    /// The running self-test or calibration sweep, if any.
+    sweep: Option<diagnostics::Sweep>,
+    self_test_results: Option<SelfTestResults>,
+    stall_detector: diagnostics::StallDetector,
+    external_changes: ExternalChangePolicy,
+    tamper_detector: tamper::TamperDetector,
+    state_dir: PathBuf,
+    calibration: Option<calibration::Calibration>,
+    /// The laptop's model name, from DMI.
+    model: String,
+    sensors: sensors::Sensors,
+    gpu_check: gpu::GpuCheck,
+    /// The fan state when the server started, if it could be read.
+    startup: Option<FanSnapshot>,
+    requests: requests::Requests,
+    /// The fan state to restore once no request is in effect and no
+    /// inhibitor is held anymore. This is only set while either is;
+    /// everything else changes this instead of the fans in the meantime.
+    unrequested: Cell<Option<FanSnapshot>>,
+    inhibitors: inhibit::Inhibitors,
+    safety_temp: Celcius,
+    /// The unique bus name of the client whose call is being handled.
+    caller: Option<String>,
+    rate_limiter: throttle::RateLimiter,
+    speed_writer: throttle::SpeedWriter,
+    stats: throttle::Stats,
+    /// When the hardware was last checked up on.
+    last_poll: Option<Instant>,
 }
 impl Controller {
     /// Creates a new D-Bus controller if possible.
-    pub fn new() -> Result<Self, anyhow::Error> {
-        let ec = ec::Ec::new().context("error setting up embedded controller")?;
-        Ok(Self {
+    pub fn new(cfg: &ServerCfg) -> Result<Self, anyhow::Error> {
+        let rules = rules::RuleEngine::new(&cfg.rules).context("invalid rule")?;
+        let schedule = schedule::Schedule::new(&cfg.schedule).context("invalid schedule")?;
+        let sensors = sensors::Sensors::new(&cfg.sensors).context("invalid sensor")?;
+        let ec = match &cfg.simulation {
+            Some(sim) => {
+                eprintln!("[info] using a simulated embedded controller");
+                ec::Ec::simulated(sim, cfg.read_only)?
+            }
+            None => ec::Ec::new(cfg.read_only).context(
+                "error setting up embedded controller (`a15kb doctor` can tell you why)",
+            )?,
+        };
+        // A broken calibration table shouldn't keep the server from starting,
+        // the fans can always be calibrated again
+        let calibration = calibration::Calibration::load(&cfg.state_dir).unwrap_or_else(|err| {
+            eprintln!("[warn] {err:#}");
+            None
+        });
+        let mut controller = Self {
             ec: RefCell::new(ec),
+            power_profiles: cfg.power_profiles.clone(),
+            pending_profile: Cell::new(None),
+            rules,
+            pre_rule: None,
+            fan_override: Cell::new(None),
+            schedule,
+            sweep: None,
+            self_test_results: None,
+            stall_detector: Default::default(),
+            external_changes: cfg.external_changes,
+            tamper_detector: Default::default(),
+            state_dir: cfg.state_dir.clone(),
+            calibration,
+            model: match cfg.simulation {
+                Some(_) => "simulated".to_string(),
+                None => std::fs::read_to_string("/sys/class/dmi/id/product_name")
+                    .map(|name| name.trim().to_string())
+                    .unwrap_or_default(),
+            },
+            sensors,
+            gpu_check: gpu::GpuCheck::new(&cfg.gpu),
+            startup: None,
+            requests: Default::default(),
+            unrequested: Cell::new(None),
+            inhibitors: Default::default(),
+            safety_temp: cfg.safety_temp,
+            caller: None,
+            rate_limiter: throttle::RateLimiter::new(cfg.rate_limit),
+            speed_writer: Default::default(),
+            stats: Default::default(),
+            last_poll: None,
+        };
+        controller.startup = controller
+            .snapshot()
+            .inspect_err(|err| eprintln!("[warn] couldn't read the fan state: {err}"))
+            .ok();
+        Ok(controller)
+    }
+
+    /// Reads the fan mode from the embedded controller.
+    fn read_fan_mode(&self) -> Result<FanMode, ec::EcError> {
+        Ok(decode_fan_modes(self.ec.borrow_mut().fan_modes()?))
+    }
+
+    /// Reads the fixed fan speed from the embedded controller.
+    /// A change from a client which hasn't been written yet counts.
+    fn read_fixed_fan_speed(&self) -> Result<f64, ec::EcError> {
+        if let Some(hw_speed) = self.speed_writer.pending() {
+            return Ok(decode_fixed_hw_speeds((hw_speed, hw_speed)));
+        }
+        Ok(decode_fixed_hw_speeds(
+            self.ec.borrow_mut().fan_fixed_hw_speeds()?,
+        ))
+    }
+
+    /// Fails if clients currently aren't allowed to change the fans
+    /// directly. `what` describes the change, for the log.
+    fn ensure_can_change(&self, what: std::fmt::Arguments) -> Result<(), dbus::MethodErr> {
+        self.ensure_can_write(what)?;
+        self.ensure_not_requested(what)
+    }
+
+    /// Fails if nobody is currently allowed to change the fans, whether
+    /// directly or through requests. `what` describes the change, for the
+    /// log.
+    fn ensure_can_write(&self, what: std::fmt::Arguments) -> Result<(), dbus::MethodErr> {
+        self.ensure_within_rate(what)?;
+        self.ensure_not_read_only(what)?;
+        if self.sweep.is_some() {
+            return Err(busy_error());
+        }
+        self.ensure_not_inhibited(what)
+    }
+
+    /// Fails if the server is running in read-only mode. `what` describes
+    /// the change, for the log.
+    fn ensure_not_read_only(&self, what: std::fmt::Arguments) -> Result<(), dbus::MethodErr> {
+        if self.ec.borrow().is_read_only() {
+            eprintln!("[info] read-only, refusing to {what}");
+            return Err(read_only_error());
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the optional subsystems which are enabled.
+    fn subsystems(&self) -> Vec<&'static str> {
+        let enabled = [
+            ("power-profiles-follow", self.power_profiles.follow),
+            ("power-profiles-publish", self.power_profiles.publish),
+            ("rules", !self.rules.rules().is_empty()),
+            ("schedule", !self.schedule.is_empty()),
+            (
+                "external-change-reassert",
+                self.external_changes == ExternalChangePolicy::Reassert,
+            ),
+        ];
+        enabled
+            .into_iter()
+            .filter_map(|(name, enabled)| enabled.then_some(name))
+            .collect()
+    }
+
+    /// Returns the calibration table, failing if the fans haven't been
+    /// calibrated.
+    fn calibration(&self) -> Result<&calibration::Calibration, dbus::MethodErr> {
+        self.calibration.as_ref().ok_or_else(not_calibrated_error)
+    }
+
+    /// Writes `fan_mode` to the embedded controller. While a request is in
+    /// effect, this only changes the state restored afterwards.
+    ///
+    /// # Panics
+    /// Panics if `fan_mode` is [`FanMode::Unknown`], since the only unknown
+    /// fan mode is one we refuse to write.
+    fn write_fan_mode(&self, fan_mode: FanMode) -> Result<(), ec::EcError> {
+        let settings = fan_mode_bits(fan_mode);
+        if let Some(unrequested) = self.unrequested.get() {
+            self.unrequested.set(Some(FanSnapshot {
+                fan_mode,
+                ..unrequested
+            }));
+            return Ok(());
+        }
+        self.ec.borrow_mut().set_fan_modes(settings)
+    }
+
+    /// Writes `fixed_fan_speed` to the embedded controller. The speed must
+    /// already be in the allowed range. While a request is in effect, this
+    /// only changes the state restored afterwards.
+    fn write_fixed_fan_speed(&self, fixed_fan_speed: f64) -> Result<(), ec::EcError> {
+        let hw_speed = fixed_hw_speed(fixed_fan_speed);
+        if let Some(unrequested) = self.unrequested.get() {
+            self.unrequested.set(Some(FanSnapshot {
+                fixed_hw_speeds: (hw_speed, hw_speed),
+                ..unrequested
+            }));
+            return Ok(());
+        }
+        self.speed_writer.cancel();
+        self.ec
+            .borrow_mut()
+            .set_fan_fixed_hw_speeds((hw_speed, hw_speed))
+    }
+
+    /// Saves the current fan state. While a request is in effect, this is
+    /// the state restored afterwards instead.
+    fn snapshot(&self) -> Result<FanSnapshot, ec::EcError> {
+        match self.unrequested.get() {
+            Some(unrequested) => Ok(unrequested),
+            None => self.read_snapshot(),
+        }
+    }
+
+    /// Reads the fan state the embedded controller is actually in, once any
+    /// pending change from a client is written.
+    fn read_snapshot(&self) -> Result<FanSnapshot, ec::EcError> {
+        self.flush_fixed_fan_speed(true)?;
+        Ok(FanSnapshot {
+            fan_mode: self.read_fan_mode()?,
+            fixed_hw_speeds: self.ec.borrow_mut().fan_fixed_hw_speeds()?,
         })
     }
+
+    /// Restores a previously saved fan state. Invalid fan states aren't
+    /// restored; the fans are set to [`FanMode::Normal`] instead. While a
+    /// request is in effect, this only changes the state restored
+    /// afterwards.
+    fn restore(&self, snapshot: FanSnapshot) -> Result<(), ec::EcError> {
+        let (hw0, hw1) = snapshot.fixed_hw_speeds;
+        let clamp = |hw: u8| hw.min(ec::HW_MAX_FAN_SPEED);
+        let snapshot = FanSnapshot {
+            fan_mode: match snapshot.fan_mode {
+                FanMode::Unknown { .. } => FanMode::Normal,
+                fan_mode => fan_mode,
+            },
+            fixed_hw_speeds: (clamp(hw0), clamp(hw1)),
+        };
+        if self.unrequested.get().is_some() {
+            self.unrequested.set(Some(snapshot));
+            return Ok(());
+        }
+        self.write_snapshot(snapshot)
+    }
+
+    /// Writes a fan state to the embedded controller, even while a request is
+    /// in effect or an inhibitor is held. Invalid fan states are written as
+    /// [`FanMode::Normal`].
+    fn write_snapshot(&self, snapshot: FanSnapshot) -> Result<(), ec::EcError> {
+        let fan_mode = match snapshot.fan_mode {
+            FanMode::Unknown { .. } => FanMode::Normal,
+            fan_mode => fan_mode,
+        };
+        self.speed_writer.cancel();
+        let mut ec = self.ec.borrow_mut();
+        ec.set_fan_fixed_hw_speeds(snapshot.fixed_hw_speeds)?;
+        ec.set_fan_modes(fan_mode_bits(fan_mode))
+    }
+
+    /// Switches to `fan_mode` at a client's request.
+    fn change_fan_mode(&self, fan_mode: FanMode) -> Result<(), dbus::MethodErr> {
+        self.ensure_can_change(format_args!("switch to {fan_mode} fans"))?;
+        self.write_fan_mode(fan_mode)?;
+        // The client made up their mind, so don't revert it later
+        self.fan_override.set(None);
+        if self.power_profiles.publish {
+            self.pending_profile
+                .set(self.power_profiles.profile_for(fan_mode));
+        }
+        Ok(())
+    }
+
+    /// Sets the fixed fan speed at a client's request.
+    fn change_fixed_fan_speed(&self, fixed_fan_speed: f64) -> Result<(), dbus::MethodErr> {
+        if !(ec::FAN_FIXED_SPEED_MIN..=ec::FAN_FIXED_SPEED_MAX).contains(&fixed_fan_speed) {
+            return Err(dbus::MethodErr::invalid_arg(&fixed_fan_speed));
+        }
+        self.ensure_can_change(format_args!(
+            "set the fixed fan speed to {:.0}%",
+            fixed_fan_speed * 100.0
+        ))?;
+        // Sliders send a lot of these
+        self.write_fixed_fan_speed_throttled(fixed_fan_speed)?;
+        Ok(())
+    }
+
+    /// Switches to `fan_mode` for `seconds` at a client's request.
+    fn override_fan_mode(&self, fan_mode: FanMode, seconds: u32) -> Result<(), dbus::MethodErr> {
+        if seconds == 0 {
+            return Err(dbus::MethodErr::invalid_arg(&seconds));
+        }
+        self.ensure_can_change(format_args!("switch to {fan_mode} fans for {seconds}s"))?;
+        let previous = match self.fan_override.get() {
+            Some(fan_override) => fan_override.previous,
+            None => self.snapshot()?,
+        };
+        self.write_fan_mode(fan_mode)?;
+        let until = Instant::now() + Duration::from_secs(seconds.into());
+        self.fan_override.set(Some(FanOverride { until, previous }));
+        Ok(())
+    }
+
+    /// Called when `power-profiles-daemon` reports a new active profile.
+    fn power_profile_changed(&mut self, profile: &str) {
+        let Some(fan_mode) = self.power_profiles.mode_for(profile) else {
+            eprintln!("[warn] unknown power profile \"{profile}\"");
+            return;
+        };
+        eprintln!("[info] power profile is now {profile}, switching to {fan_mode} fans");
+        // Like with the schedule, a sweep or an override gets to finish first
+        if let Some(sweep) = &mut self.sweep {
+            sweep.set_previous(FanSnapshot {
+                fan_mode,
+                ..sweep.previous()
+            });
+        } else if let Some(fan_override) = self.fan_override.get() {
+            self.fan_override.set(Some(FanOverride {
+                previous: FanSnapshot {
+                    fan_mode,
+                    ..fan_override.previous
+                },
+                ..fan_override
+            }));
+        } else if let Err(err) = self.write_fan_mode(fan_mode) {
+            eprintln!("[warn] couldn't switch fans for power profile {profile}: {err}");
+        }
+    }
+
+    /// Applies the active per-application rule, or restores the fan state
+    /// from before any rule took effect.
+    fn apply_rule(&mut self) -> Result<(), ec::EcError> {
+        match self.rules.active() {
+            Some(rule) => {
+                eprintln!("[info] rule \"{}\" is now in effect", rule.name);
+                if self.pre_rule.is_none() {
+                    self.pre_rule = Some(self.snapshot()?);
+                }
+                if let Some(fixed_fan_speed) = rule.fixed_speed {
+                    self.write_fixed_fan_speed(fixed_fan_speed)?;
+                }
+                self.write_fan_mode(rule.fan_mode)
+            }
+            None => {
+                eprintln!("[info] no rule is in effect anymore");
+                match self.pre_rule.take() {
+                    Some(snapshot) => self.restore(snapshot),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Switches to a scheduled fan state. If an override or a rule is in
+    /// effect, the scheduled state is restored once it ends instead.
+    fn apply_scheduled(&mut self, entry: &ScheduleEntryCfg) -> Result<(), ec::EcError> {
+        eprintln!("[info] switching to scheduled {} fans", entry.fan_mode);
+        let fixed_hw_speeds = match entry.fixed_speed {
+            Some(fixed_fan_speed) => {
+                let hw_speed = fixed_hw_speed(fixed_fan_speed);
+                (hw_speed, hw_speed)
+            }
+            None => self.ec.borrow_mut().fan_fixed_hw_speeds()?,
+        };
+        let scheduled = FanSnapshot {
+            fan_mode: entry.fan_mode,
+            fixed_hw_speeds,
+        };
+        if let Some(fan_override) = self.fan_override.get() {
+            self.fan_override.set(Some(FanOverride {
+                previous: scheduled,
+                ..fan_override
+            }));
+        } else if self.pre_rule.is_some() {
+            self.pre_rule = Some(scheduled);
+        } else {
+            self.restore(scheduled)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a self-test or calibration sweep.
+    fn start_sweep(&mut self, purpose: diagnostics::SweepPurpose) -> Result<(), dbus::MethodErr> {
+        self.ensure_can_change(format_args!("start a {purpose:?} sweep"))?;
+        let previous = self.snapshot()?;
+        let hw_speeds = match purpose {
+            diagnostics::SweepPurpose::SelfTest => diagnostics::self_test_hw_speeds(),
+            diagnostics::SweepPurpose::Calibration => calibration::Calibration::sweep_hw_speeds(),
+        };
+        let sweep = diagnostics::Sweep::start(self.ec.get_mut(), purpose, hw_speeds, previous)?;
+        self.sweep = Some(sweep);
+        Ok(())
+    }
+
+    /// Advances the running sweep, if any. Once it's done, the results are
+    /// saved and the previous fan state is restored.
+    fn poll_sweep(&mut self, now: Instant) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        let purpose = sweep.purpose();
+        match (sweep.poll(now, self.ec.get_mut()), purpose) {
+            (Ok(None), _) => return,
+            (Ok(Some(steps)), diagnostics::SweepPurpose::SelfTest) => {
+                let results = diagnostics::self_test_results(&steps);
+                eprintln!(
+                    "[info] self-test finished, fan health: {:?}",
+                    results.health
+                );
+                self.self_test_results = Some(results);
+            }
+            (Ok(Some(steps)), diagnostics::SweepPurpose::Calibration) => {
+                match calibration::Calibration::from_steps(&steps) {
+                    Some(calibration) => {
+                        eprintln!("[info] calibration finished");
+                        if let Err(err) = calibration.save(&self.state_dir) {
+                            eprintln!("[warn] {err:#}");
+                        }
+                        self.calibration = Some(calibration);
+                    }
+                    None => eprintln!("[warn] calibration failed, a fan never spun"),
+                }
+            }
+            (Err(_), _) => eprintln!("[warn] {purpose:?} sweep aborted"),
+        }
+        if let Some(sweep) = self.sweep.take() {
+            let _ = self.restore(sweep.previous());
+        }
+    }
+
+    /// Checks up on the hardware. This is called every [`POLL_INTERVAL`].
+    fn poll_hardware(&mut self, now: Instant, cxn: &Connection) -> Result<(), ec::EcError> {
+        let fan_mode = self.read_fan_mode()?;
+        let fixed_fan_speed = self.read_fixed_fan_speed()?;
+        let fan_rpm = self.ec.get_mut().fan_rpm()?;
+        let stalled = self
+            .stall_detector
+            .update(now, fan_mode, fixed_fan_speed, fan_rpm);
+        for fan in stalled {
+            eprintln!("[warn] fan {fan} reads 0 RPM at fixed speed {fixed_fan_speed}");
+            let warning = server_generated::ComOffbyondA15kbController1FanStallWarning {
+                fan,
+                fixed_fan_speed,
+            };
+            let _ = cxn.send(warning.to_emit_message(&CONTROLLER_PATH.into()));
+        }
+        if let Some((written, actual)) = self.tamper_detector.check(self.ec.get_mut())? {
+            eprintln!("[warn] fan registers changed behind our back: {written:?} -> {actual:?}");
+            let reasserted = self.external_changes == ExternalChangePolicy::Reassert
+                && written.write(self.ec.get_mut()).is_ok();
+            let fan_mode = |registers: tamper::FanRegisters| registers.fan_mode().to_discriminant();
+            let detected = server_generated::ComOffbyondA15kbController1ExternalChangeDetected {
+                before_fan_mode: fan_mode(written),
+                before_fixed_fan_speed: written.fixed_fan_speed(),
+                after_fan_mode: fan_mode(actual),
+                after_fixed_fan_speed: actual.fixed_fan_speed(),
+                reasserted,
+                before_fan_mode_bits: written.fan_modes,
+                after_fan_mode_bits: actual.fan_modes,
+            };
+            let _ = cxn.send(detected.to_emit_message(&CONTROLLER_PATH.into()));
+        }
+        Ok(())
+    }
+
+    /// Runs periodic tasks. This is called at least once every [`TICK`].
+    fn tick(&mut self, cxn: &Connection) {
+        let now = Instant::now();
+        if self
+            .last_poll
+            .is_none_or(|last_poll| now.duration_since(last_poll) >= POLL_INTERVAL)
+        {
+            self.last_poll = Some(now);
+            if let Err(err) = self.poll_hardware(now, cxn) {
+                eprintln!("[warn] couldn't check up on the hardware: {err}");
+            }
+            if let Err(err) = self.poll_safety() {
+                eprintln!("[warn] thermal safety override failed: {err}");
+            }
+        }
+        self.poll_inhibitors();
+        let _ = self.flush_fixed_fan_speed(false);
+        if self.sweep.is_some() {
+            // Nothing else gets to touch the fans until the sweep is done
+            self.poll_sweep(now);
+            return;
+        }
+        if let Some(fan_override) = self.fan_override.get() {
+            if now >= fan_override.until {
+                eprintln!("[info] fan mode override expired");
+                self.fan_override.set(None);
+                if let Err(err) = self.restore(fan_override.previous) {
+                    eprintln!("[warn] couldn't restore the fans after the override: {err}");
+                }
+            }
+        }
+        if self.rules.poll(now) {
+            if let Err(err) = self.apply_rule() {
+                eprintln!("[warn] couldn't apply rules: {err}");
+            }
+        }
+        if let Some(entry) = self.schedule.poll(chrono::Local::now()) {
+            let entry = entry.clone();
+            if let Err(err) = self.apply_scheduled(&entry) {
+                eprintln!("[warn] couldn't switch to scheduled fans: {err}");
+            }
+        }
+        if let Some(profile) = self.pending_profile.take() {
+            if let Err(err) = power_profiles::publish(cxn, profile) {
+                eprintln!("[warn] couldn't set power profile to {profile}: {err}");
+            }
+        }
+    }
 }
 impl server_generated::ComOffbyondA15kbController1 for Controller {
     fn get_thermal_info(&mut self) -> Result<(u8, u8, (u16, u16)), dbus::MethodErr> {
         let ec = self.ec.get_mut();
-        Ok((ec.temp_cpu()?, ec.temp_gpu()?, ec.fan_rpm()?))
+        let temp_gpu = self.gpu_check.temp(ec.temp_gpu()?).temp_or_zero();
+        Ok((ec.temp_cpu()?, temp_gpu, ec.fan_rpm()?))
     }
     fn fan_mode(&self) -> Result<u8, dbus::MethodErr> {
-        let fan_mode = match self.ec.borrow_mut().fan_modes()? {
-            // (quiet, gaming, fixed)
-            (false, false, false) => FanMode::Normal.to_discriminant(),
-            (true, false, false) => FanMode::Quiet.to_discriminant(),
-            (false, true, false) => FanMode::Gaming.to_discriminant(),
-            (true, true, false) => u8::MAX, // quiet AND gaming?
-            (_, _, true) => FanMode::Fixed.to_discriminant(),
-        };
-        Ok(fan_mode)
+        Ok(self.read_fan_mode()?.to_discriminant())
+    }
+    fn raw_fan_mode_bits(&self) -> Result<(bool, bool, bool), dbus::MethodErr> {
+        Ok(self.ec.borrow_mut().fan_modes()?)
     }
     fn set_fan_mode(&self, fan_mode: u8) -> Result<(), dbus::MethodErr> {
-        let settings = match FanMode::from_discriminant(fan_mode) {
-            Some(FanMode::Quiet) => (true, false, false),
-            Some(FanMode::Normal) => (false, false, false),
-            Some(FanMode::Gaming) => (false, true, false),
-            Some(FanMode::Fixed) => (false, false, true),
-            None => return Err(dbus::MethodErr::invalid_arg(&fan_mode)),
+        let Some(fan_mode) = FanMode::from_discriminant(fan_mode) else {
+            return Err(dbus::MethodErr::invalid_arg(&fan_mode));
         };
-        self.ec.borrow_mut().set_fan_modes(settings)?;
-        Ok(())
+        self.change_fan_mode(fan_mode)
     }
     fn fixed_fan_speed(&self) -> Result<f64, dbus::MethodErr> {
-        let fixed_fan_speed = {
-            // TODO: Maybe expose each fan's speed individually?
-            let (hw0, hw1) = self.ec.borrow_mut().fan_fixed_hw_speeds()?;
-            let fl0 = (hw0 as f64) / (ec::HW_MAX_FAN_SPEED as f64);
-            let fl1 = (hw1 as f64) / (ec::HW_MAX_FAN_SPEED as f64);
-            0.5 * (fl0 + fl1)
-        };
-        Ok(fixed_fan_speed)
+        Ok(self.read_fixed_fan_speed()?)
     }
     fn set_fixed_fan_speed(&self, fixed_fan_speed: f64) -> Result<(), dbus::MethodErr> {
-        if !(ec::FAN_FIXED_SPEED_MIN..=ec::FAN_FIXED_SPEED_MAX).contains(&fixed_fan_speed) {
-            return Err(dbus::MethodErr::invalid_arg(&fixed_fan_speed));
-        }
-        let fhw_speed = fixed_fan_speed * (ec::HW_MAX_FAN_SPEED as f64);
-        let hw_speed = fhw_speed as u8;
-        self.ec
-            .borrow_mut()
-            .set_fan_fixed_hw_speeds((hw_speed, hw_speed))?;
-        Ok(())
+        self.change_fixed_fan_speed(fixed_fan_speed)
     }
     fn allowed_fixed_fan_speeds(&self) -> Result<(f64, f64), dbus::MethodErr> {
         Ok((ec::FAN_FIXED_SPEED_MIN, ec::FAN_FIXED_SPEED_MAX))
     }
+    fn set_fan_mode_for(&mut self, fan_mode: u8, seconds: u32) -> Result<(), dbus::MethodErr> {
+        let Some(fan_mode) = FanMode::from_discriminant(fan_mode) else {
+            return Err(dbus::MethodErr::invalid_arg(&fan_mode));
+        };
+        self.override_fan_mode(fan_mode, seconds)
+    }
+    fn override_remaining(&self) -> Result<u32, dbus::MethodErr> {
+        let remaining = self
+            .fan_override
+            .get()
+            .map_or(Duration::ZERO, |fan_override| {
+                fan_override.until.saturating_duration_since(Instant::now())
+            });
+        // Round up, so that we don't report 0 while there's still an override
+        let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        Ok(seconds.try_into().unwrap_or(u32::MAX))
+    }
+    fn next_scheduled_change(&self) -> Result<(i64, u8, f64), dbus::MethodErr> {
+        let next = self.schedule.next_after(chrono::Local::now());
+        Ok(next.map_or((0, u8::MAX, -1.0), |(at, entry)| {
+            (
+                at.timestamp(),
+                entry.fan_mode.to_discriminant(),
+                entry.fixed_speed.unwrap_or(-1.0),
+            )
+        }))
+    }
+    fn start_self_test(&mut self) -> Result<(), dbus::MethodErr> {
+        self.start_sweep(diagnostics::SweepPurpose::SelfTest)?;
+        eprintln!("[info] starting self-test");
+        Ok(())
+    }
+    fn self_test_running(&self) -> Result<bool, dbus::MethodErr> {
+        let purpose = self.sweep.as_ref().map(diagnostics::Sweep::purpose);
+        Ok(purpose == Some(diagnostics::SweepPurpose::SelfTest))
+    }
+    #[allow(clippy::type_complexity)]
+    fn get_self_test_results(
+        &mut self,
+    ) -> Result<(i64, Vec<(f64, u16, u16)>, (u8, u8)), dbus::MethodErr> {
+        let Some(results) = &self.self_test_results else {
+            return Ok((0, Vec::new(), (u8::MAX, u8::MAX)));
+        };
+        let finished = results
+            .finished
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+        let steps = results.steps.iter().map(|step| {
+            let (rpm0, rpm1) = step.fan_rpm;
+            (step.fixed_fan_speed.as_f64(), rpm0, rpm1)
+        });
+        let health = |health: Option<FanHealth>| health.map_or(u8::MAX, FanHealth::to_discriminant);
+        Ok((
+            finished,
+            steps.collect(),
+            (health(results.health.0), health(results.health.1)),
+        ))
+    }
+    fn start_calibration(&mut self) -> Result<(), dbus::MethodErr> {
+        self.start_sweep(diagnostics::SweepPurpose::Calibration)?;
+        eprintln!("[info] starting calibration");
+        Ok(())
+    }
+    fn calibrating(&self) -> Result<bool, dbus::MethodErr> {
+        let purpose = self.sweep.as_ref().map(diagnostics::Sweep::purpose);
+        Ok(purpose == Some(diagnostics::SweepPurpose::Calibration))
+    }
+    fn fixed_fan_rpm(&self) -> Result<(u16, u16), dbus::MethodErr> {
+        let calibration = self.calibration()?;
+        let (hw0, hw1) = self.ec.borrow_mut().fan_fixed_hw_speeds()?;
+        Ok((calibration.rpm_for(0, hw0), calibration.rpm_for(1, hw1)))
+    }
+    fn set_target_fan_rpm(&mut self, rpm: u16) -> Result<(), dbus::MethodErr> {
+        let calibration = self.calibration()?;
+        let (min, max) = calibration.rpm_range();
+        if !(min..=max).contains(&rpm) {
+            return Err(dbus::MethodErr::invalid_arg(&rpm));
+        }
+        // Each fan gets its own speed, since they don't spin up the same
+        let min_hw = fixed_hw_speed(ec::FAN_FIXED_SPEED_MIN);
+        let max_hw = fixed_hw_speed(ec::FAN_FIXED_SPEED_MAX);
+        let hw_speed = |fan| calibration.hw_speed_for(fan, rpm).clamp(min_hw, max_hw);
+        let hw_speeds = (hw_speed(0), hw_speed(1));
+        self.ensure_can_change(format_args!("set the fans to {rpm} RPM"))?;
+        self.speed_writer.cancel();
+        self.ec.borrow_mut().set_fan_fixed_hw_speeds(hw_speeds)?;
+        Ok(())
+    }
+    fn allowed_fixed_fan_rpms(&self) -> Result<(u16, u16), dbus::MethodErr> {
+        Ok(self.calibration()?.rpm_range())
+    }
+    fn active_rule(&self) -> Result<String, dbus::MethodErr> {
+        let active = self.rules.active();
+        Ok(active.map(|rule| rule.name.clone()).unwrap_or_default())
+    }
+    #[allow(clippy::type_complexity)]
+    fn get_rules(
+        &mut self,
+    ) -> Result<Vec<(String, i32, String, String, u8, f64)>, dbus::MethodErr> {
+        let rules = self.rules.rules().iter().map(|rule| {
+            (
+                rule.name.clone(),
+                rule.priority,
+                rule.exe.clone().unwrap_or_default(),
+                rule.cgroup.clone().unwrap_or_default(),
+                rule.fan_mode.to_discriminant(),
+                rule.fixed_speed.unwrap_or(-1.0),
+            )
+        });
+        Ok(rules.collect())
+    }
 }
@@ -0,0 +1,136 @@
+//! Calibration tables which map hardware fan speeds to RPMs. The mapping
+//! isn't linear (and each fan has its own), so the only way to find it is to
+//! sweep through the speed range and measure.
+use super::*;
+use serde::Serialize;
+use std::fs;
+
+/// How many speeds the calibration sweep measures.
+const CALIBRATION_STEPS: u8 = 8;
+
+/// The name of the calibration table inside the state directory.
+const CALIBRATION_FILE: &str = "calibration.toml";
+
+/// A single measured point in the calibration table.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CalibrationPoint {
+    hw_speed: u8,
+    /// The RPM of the left and right fans, respectively.
+    rpm: (u16, u16),
+}
+
+/// Maps hardware fan speeds to RPMs for each fan.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct Calibration {
+    /// Sorted by hardware speed. The RPMs never decrease from one point to
+    /// the next.
+    points: Vec<CalibrationPoint>,
+}
+
+impl Calibration {
+    /// Returns the hardware speeds the calibration sweep measures, covering
+    /// the allowed fixed speed range.
+    pub fn sweep_hw_speeds() -> Vec<u8> {
+        let min = fixed_hw_speed(ec::FAN_FIXED_SPEED_MIN);
+        let max = fixed_hw_speed(ec::FAN_FIXED_SPEED_MAX);
+        let steps = u32::from(CALIBRATION_STEPS - 1);
+        (0..=steps)
+            .map(|step| {
+                let offset = u32::from(max - min) * step / steps;
+                min + offset as u8
+            })
+            .collect()
+    }
+
+    /// Builds a calibration table from the measurements of a sweep. Returns
+    /// [`None`] if a fan never spun.
+    pub fn from_steps(steps: &[diagnostics::SweepStep]) -> Option<Self> {
+        let mut points: Vec<_> = steps
+            .iter()
+            .map(|step| CalibrationPoint {
+                hw_speed: step.hw_speed,
+                rpm: step.fan_rpm,
+            })
+            .collect();
+        points.sort_by_key(|point| point.hw_speed);
+        // Measurements are a bit noisy, but a faster speed should never mean
+        // fewer RPM. Flatten out any dips so the table can be inverted.
+        for idx in 1..points.len() {
+            let prev = points[idx - 1].rpm;
+            let rpm = &mut points[idx].rpm;
+            *rpm = (rpm.0.max(prev.0), rpm.1.max(prev.1));
+        }
+        let last = points.last()?;
+        (last.rpm.0 > 0 && last.rpm.1 > 0).then_some(Self { points })
+    }
+
+    /// Loads the calibration table from the state directory. Returns
+    /// [`None`] if the fans haven't been calibrated yet.
+    pub fn load(state_dir: &Path) -> Result<Option<Self>, anyhow::Error> {
+        let path = state_dir.join(CALIBRATION_FILE);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("couldn't read calibration table"),
+        };
+        let calibration: Self =
+            toml::from_str(&text).context("couldn't parse calibration table")?;
+        anyhow::ensure!(!calibration.points.is_empty(), "calibration table is empty");
+        Ok(Some(calibration))
+    }
+
+    /// Saves the calibration table to the state directory.
+    pub fn save(&self, state_dir: &Path) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(state_dir).context("couldn't create state directory")?;
+        let text = toml::to_string(self).context("couldn't serialize calibration table")?;
+        fs::write(state_dir.join(CALIBRATION_FILE), text)
+            .context("couldn't write calibration table")
+    }
+
+    /// Returns the `(hw_speed, rpm)` points for the given fan.
+    fn curve(&self, fan: usize) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.points.iter().map(move |point| {
+            let rpm = if fan == 0 { point.rpm.0 } else { point.rpm.1 };
+            (f64::from(point.hw_speed), f64::from(rpm))
+        })
+    }
+
+    /// Returns the approximate RPM of the given fan at `hw_speed`.
+    pub fn rpm_for(&self, fan: usize, hw_speed: u8) -> u16 {
+        interpolate(self.curve(fan), f64::from(hw_speed)) as u16
+    }
+
+    /// Returns the hardware speed which gets the given fan closest to `rpm`.
+    pub fn hw_speed_for(&self, fan: usize, rpm: u16) -> u8 {
+        let curve = self.curve(fan).map(|(hw, rpm)| (rpm, hw));
+        interpolate(curve, f64::from(rpm)).round() as u8
+    }
+
+    /// Returns the range of RPMs which both fans can reach within the allowed
+    /// fixed speed range.
+    pub fn rpm_range(&self) -> (u16, u16) {
+        let min_hw = fixed_hw_speed(ec::FAN_FIXED_SPEED_MIN);
+        let max_hw = fixed_hw_speed(ec::FAN_FIXED_SPEED_MAX);
+        let min = self.rpm_for(0, min_hw).max(self.rpm_for(1, min_hw));
+        let max = self.rpm_for(0, max_hw).min(self.rpm_for(1, max_hw));
+        (min, max.max(min))
+    }
+}
+
+/// Linearly interpolates `x` along a curve of `(x, y)` points sorted by `x`.
+/// Values outside the curve are clamped to its ends.
+fn interpolate(curve: impl Iterator<Item = (f64, f64)>, x: f64) -> f64 {
+    let mut prev: Option<(f64, f64)> = None;
+    for (x1, y1) in curve {
+        if x <= x1 {
+            return match prev {
+                Some((x0, y0)) if x1 > x0 => y0 + (y1 - y0) * (x - x0) / (x1 - x0),
+                _ => y1,
+            };
+        }
+        prev = Some((x1, y1));
+    }
+    prev.map_or(0.0, |(_, y)| y)
+}
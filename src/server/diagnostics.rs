@@ -0,0 +1,199 @@
+//! Fan health checks. There's a self-test which sweeps the fans through a few
+//! fixed speeds and compares them, and a stall detector which keeps an eye on
+//! the fans while they're in fixed-speed mode.
+use super::*;
+
+/// The fixed speeds the self-test steps through.
+const SELF_TEST_SPEEDS: [f64; 4] = [0.3, 0.5, 0.75, 1.0];
+
+/// How long to let the fans settle at each step of a sweep before measuring
+/// them.
+const SWEEP_SETTLE: Duration = Duration::from_secs(7);
+
+/// A fan is weak if it spins slower than this fraction of the other fan.
+const WEAK_RATIO: f64 = 0.7;
+
+/// The stall detector only cares about fixed speeds at least this high, since
+/// (I think) the fans may legitimately stop at low speeds.
+const STALL_MIN_FIXED_SPEED: f64 = 0.5;
+
+/// How long a fan has to read 0 RPM before it's considered stalled. The fans
+/// take a couple seconds to spin up after a speed change.
+const STALL_GRACE: Duration = Duration::from_secs(5);
+
+/// What a [`Sweep`] is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SweepPurpose {
+    SelfTest,
+    Calibration,
+}
+
+/// A single measurement taken during a [`Sweep`].
+#[derive(Debug, Clone, Copy)]
+pub(super) struct SweepStep {
+    /// The hardware speed both fans were set to.
+    pub hw_speed: u8,
+    /// The resulting RPM of the left and right fans, respectively.
+    pub fan_rpm: (u16, u16),
+}
+
+/// Steps both fans through a list of fixed speeds, measuring their RPM at
+/// each one. Used by the self-test and by calibration.
+pub(super) struct Sweep {
+    purpose: SweepPurpose,
+    /// The hardware speeds to step through.
+    hw_speeds: Vec<u8>,
+    /// The fan state to restore once the sweep is done.
+    previous: FanSnapshot,
+    /// The measurements from each finished step.
+    steps: Vec<SweepStep>,
+    /// When the current step started.
+    step_started: Instant,
+}
+
+impl Sweep {
+    /// Starts a new sweep by switching to the first speed.
+    ///
+    /// # Panics
+    /// Panics if `hw_speeds` is empty or contains a speed greater than
+    /// [`ec::HW_MAX_FAN_SPEED`].
+    pub fn start(
+        ec: &mut ec::Ec,
+        purpose: SweepPurpose,
+        hw_speeds: Vec<u8>,
+        previous: FanSnapshot,
+    ) -> Result<Self, ec::EcError> {
+        let hw_speed = hw_speeds[0];
+        ec.set_fan_fixed_hw_speeds((hw_speed, hw_speed))?;
+        ec.set_fan_modes((false, false, true))?;
+        Ok(Self {
+            purpose,
+            steps: Vec::with_capacity(hw_speeds.len()),
+            hw_speeds,
+            previous,
+            step_started: Instant::now(),
+        })
+    }
+
+    /// Returns what the sweep is for.
+    pub fn purpose(&self) -> SweepPurpose {
+        self.purpose
+    }
+
+    /// Returns the fan state from before the sweep started.
+    pub fn previous(&self) -> FanSnapshot {
+        self.previous
+    }
+
+    /// Replaces the fan state to restore once the sweep is done.
+    pub fn set_previous(&mut self, previous: FanSnapshot) {
+        self.previous = previous;
+    }
+
+    /// Measures the current step if the fans have had time to settle, then
+    /// moves on to the next step. Returns the measurements once every step
+    /// is done.
+    pub fn poll(
+        &mut self,
+        now: Instant,
+        ec: &mut ec::Ec,
+    ) -> Result<Option<Vec<SweepStep>>, ec::EcError> {
+        if now.duration_since(self.step_started) < SWEEP_SETTLE {
+            return Ok(None);
+        }
+        self.steps.push(SweepStep {
+            hw_speed: self.hw_speeds[self.steps.len()],
+            fan_rpm: ec.fan_rpm()?,
+        });
+        let Some(&hw_speed) = self.hw_speeds.get(self.steps.len()) else {
+            return Ok(Some(std::mem::take(&mut self.steps)));
+        };
+        ec.set_fan_fixed_hw_speeds((hw_speed, hw_speed))?;
+        self.step_started = now;
+        Ok(None)
+    }
+}
+
+/// Returns the hardware speeds the self-test steps through.
+pub(super) fn self_test_hw_speeds() -> Vec<u8> {
+    SELF_TEST_SPEEDS.into_iter().map(fixed_hw_speed).collect()
+}
+
+/// Turns the measurements from a self-test sweep into results.
+pub(super) fn self_test_results(steps: &[SweepStep]) -> SelfTestResults {
+    let steps: Vec<_> = steps
+        .iter()
+        .map(|step| SelfTestStep {
+            fixed_fan_speed: Percent(f64::from(step.hw_speed) / f64::from(ec::HW_MAX_FAN_SPEED)),
+            fan_rpm: step.fan_rpm,
+        })
+        .collect();
+    SelfTestResults {
+        finished: SystemTime::now(),
+        health: assess(&steps),
+        steps,
+    }
+}
+
+/// Judges the health of the left and right fans from self-test measurements.
+fn assess(steps: &[SelfTestStep]) -> (Option<FanHealth>, Option<FanHealth>) {
+    // Compared after rounding to a hardware speed, like the steps were
+    let min_hw_speed = fixed_hw_speed(STALL_MIN_FIXED_SPEED);
+    let should_spin = |step: &SelfTestStep| {
+        step.fixed_fan_speed.as_f64() >= f64::from(min_hw_speed) / f64::from(ec::HW_MAX_FAN_SPEED)
+    };
+    let health = |rpm: fn(&SelfTestStep) -> (u16, u16)| {
+        let stalled = steps
+            .iter()
+            .any(|step| should_spin(step) && rpm(step).0 == 0);
+        let weak = steps.iter().any(|step| {
+            let (this, other) = rpm(step);
+            f64::from(this) < WEAK_RATIO * f64::from(other)
+        });
+        match (stalled, weak) {
+            (true, _) => FanHealth::Stalled,
+            (false, true) => FanHealth::Weak,
+            (false, false) => FanHealth::Healthy,
+        }
+    };
+    (
+        Some(health(|step| step.fan_rpm)),
+        Some(health(|step| (step.fan_rpm.1, step.fan_rpm.0))),
+    )
+}
+
+/// Watches for fans which read 0 RPM while they should be spinning fast.
+#[derive(Default)]
+pub(super) struct StallDetector {
+    /// For each fan, when it was first seen stalled, and whether we've
+    /// already warned about it.
+    stalled_since: [Option<(Instant, bool)>; 2],
+}
+
+impl StallDetector {
+    /// Updates the detector with the latest readings. Returns the indices of
+    /// fans which were just found to be stalled.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        fan_mode: FanMode,
+        fixed_fan_speed: f64,
+        fan_rpm: (u16, u16),
+    ) -> Vec<u8> {
+        let should_spin = fan_mode == FanMode::Fixed && fixed_fan_speed >= STALL_MIN_FIXED_SPEED;
+        let mut newly_stalled = Vec::new();
+        for (fan, rpm) in [(0, fan_rpm.0), (1, fan_rpm.1)] {
+            let stalled_since = &mut self.stalled_since[usize::from(fan)];
+            if !should_spin || rpm != 0 {
+                *stalled_since = None;
+                continue;
+            }
+            let (since, warned) = stalled_since.get_or_insert((now, false));
+            if !*warned && now.duration_since(*since) >= STALL_GRACE {
+                *warned = true;
+                newly_stalled.push(fan);
+            }
+        }
+        newly_stalled
+    }
+}
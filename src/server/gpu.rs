@@ -0,0 +1,89 @@
+//! Figuring out whether the dGPU is on and how hot it is. The EC reports 0
+//! when the dGPU is off, but it sometimes reports 0 (or a stale temperature)
+//! while it's on, so we can optionally cross-check with the kernel.
+use super::*;
+use std::fs;
+
+/// Where PCI devices live.
+const PCI_DIR: &str = "/sys/bus/pci/devices";
+
+/// The configuration for the dGPU cross-check.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpuCfg {
+    /// Whether to cross-check the EC's dGPU temperature with the dGPU's
+    /// runtime power state and hwmon sensor.
+    pub cross_check: bool,
+
+    /// The dGPU's PCI address, e.g. `0000:01:00.0`. By default, the first
+    /// display controller which isn't the boot VGA device is used.
+    pub pci_device: Option<String>,
+}
+
+/// Cross-checks the EC's dGPU temperature, if configured to.
+pub(super) struct GpuCheck {
+    /// The dGPU's sysfs directory. This is [`None`] if the cross-check is
+    /// disabled or the dGPU couldn't be found.
+    device: Option<PathBuf>,
+}
+
+impl GpuCheck {
+    pub fn new(cfg: &GpuCfg) -> Self {
+        if !cfg.cross_check {
+            return Self { device: None };
+        }
+        let device = match &cfg.pci_device {
+            Some(address) => Some(Path::new(PCI_DIR).join(address)).filter(|path| path.exists()),
+            None => find_dgpu(),
+        };
+        match &device {
+            Some(device) => eprintln!("[info] cross-checking dGPU at {}", device.display()),
+            None => eprintln!("[warn] couldn't find the dGPU, not cross-checking it"),
+        }
+        Self { device }
+    }
+
+    /// Works out the state of the dGPU, given the temperature the EC reports.
+    pub fn temp(&self, ec_temp: u8) -> GpuTemp {
+        let Some(device) = &self.device else {
+            return GpuTemp::from_ec(ec_temp);
+        };
+        // Whatever the EC says, a suspended dGPU is off
+        let status = fs::read_to_string(device.join("power/runtime_status"));
+        if status.is_ok_and(|status| status.trim() == "suspended") {
+            return GpuTemp::Off;
+        }
+        if let Some(temp) = hwmon_temp(device) {
+            return GpuTemp::Temp(temp);
+        }
+        match ec_temp {
+            0 => GpuTemp::Unknown,
+            temp => GpuTemp::Temp(temp),
+        }
+    }
+}
+
+/// Finds the first display controller which isn't the boot VGA device. On
+/// these laptops, that's the dGPU.
+fn find_dgpu() -> Option<PathBuf> {
+    let mut devices: Vec<_> = fs::read_dir(PCI_DIR)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    devices.sort();
+    devices.into_iter().find(|device| {
+        let read = |name| fs::read_to_string(device.join(name)).unwrap_or_default();
+        // 0x03xxxx is the "display controller" class
+        read("class").starts_with("0x03") && read("boot_vga").trim() != "1"
+    })
+}
+
+/// Reads the dGPU's temperature from its hwmon sensor, if the driver
+/// provides one.
+fn hwmon_temp(device: &Path) -> Option<u8> {
+    let hwmon = fs::read_dir(device.join("hwmon")).ok()?.flatten().next()?;
+    let millidegrees = fs::read_to_string(hwmon.path().join("temp1_input")).ok()?;
+    let millidegrees: i64 = millidegrees.trim().parse().ok()?;
+    Some((millidegrees / 1000).clamp(0, u8::MAX.into()) as u8)
+}
@@ -0,0 +1,206 @@
+//! Inhibitor locks, modeled on logind's. A client gets a file descriptor,
+//! and the fans stay exactly as they were until every copy of it is closed:
+//! nothing else (clients, requests, rules, the schedule, ...) can change
+//! them in the meantime. The only exception is the thermal safety override,
+//! since a laptop pinned to quiet fans under load shouldn't cook itself.
+use super::*;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// The default temperature at which the thermal safety override kicks in.
+pub(super) const DEFAULT_SAFETY_TEMP: Celcius = 90;
+
+/// How far the temperature has to drop below the safety temperature before
+/// the inhibitors get the fans back, so the override doesn't flap.
+const SAFETY_HYSTERESIS: Celcius = 10;
+
+/// A client's inhibitor lock.
+struct Inhibitor {
+    id: u32,
+    /// The unique bus name of the client which took the lock.
+    owner: String,
+    reason: String,
+    /// Our end of the pipe handed to the client. It hangs up once every
+    /// copy of the client's end is closed.
+    pipe: OwnedFd,
+}
+
+/// Every held inhibitor lock, and the fan state they pin.
+#[derive(Default)]
+pub(super) struct Inhibitors {
+    inhibitors: Vec<Inhibitor>,
+    next_id: u32,
+    /// The pinned fan state. This is only set while a lock is held.
+    pinned: Option<FanSnapshot>,
+    /// Whether the thermal safety override has taken the fans away from the
+    /// inhibitors.
+    overridden: bool,
+}
+
+impl Inhibitors {
+    /// Returns whether any lock is held.
+    pub fn is_held(&self) -> bool {
+        self.pinned.is_some()
+    }
+
+    /// Removes the locks whose pipes were hung up, returning how many there
+    /// were.
+    fn reap(&mut self) -> usize {
+        let mut fds: Vec<_> = self
+            .inhibitors
+            .iter()
+            .map(|inhibitor| libc::pollfd {
+                fd: inhibitor.pipe.as_raw_fd(),
+                events: 0,
+                revents: 0,
+            })
+            .collect();
+        // SAFETY: fds is a valid array of fds.len() pollfds
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 0) };
+        if ready <= 0 {
+            return 0;
+        }
+        let mut hung_up = fds
+            .iter()
+            .map(|fd| fd.revents & (libc::POLLHUP | libc::POLLERR) != 0);
+        let before = self.inhibitors.len();
+        self.inhibitors.retain(|inhibitor| {
+            let gone = hung_up.next().unwrap_or(false);
+            if gone {
+                eprintln!(
+                    "[info] inhibitor {} from {} ({}) released",
+                    inhibitor.id, inhibitor.owner, inhibitor.reason
+                );
+            }
+            !gone
+        });
+        before - self.inhibitors.len()
+    }
+}
+
+/// Returns the error for attempting to change the fans while they're
+/// pinned.
+fn inhibited_error(inhibitor: &Inhibitor) -> dbus::MethodErr {
+    (
+        "com.offbyond.a15kb.Error.Inhibited",
+        format!(
+            "the fans are pinned by inhibitor {} from {} ({})",
+            inhibitor.id, inhibitor.owner, inhibitor.reason
+        ),
+    )
+        .into()
+}
+
+/// Creates a pipe, returning `(read end, write end)`. Neither end is
+/// inherited by child processes.
+fn pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two fds pipe2 writes
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just opened these, and nothing else owns them
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+impl Controller {
+    /// Fails if an inhibitor lock is held. `what` describes the change, for
+    /// the log.
+    pub(super) fn ensure_not_inhibited(
+        &self,
+        what: std::fmt::Arguments,
+    ) -> Result<(), dbus::MethodErr> {
+        match self.inhibitors.inhibitors.first() {
+            Some(inhibitor) => {
+                eprintln!(
+                    "[info] inhibitor {} is held, refusing to {what}",
+                    inhibitor.id
+                );
+                Err(inhibited_error(inhibitor))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Pins the fans in their current state for the client whose call is
+    /// being handled, until it closes the returned file descriptor.
+    pub(super) fn inhibit(
+        &mut self,
+        reason: String,
+    ) -> Result<dbus::arg::OwnedFd, dbus::MethodErr> {
+        // Not ensure_can_write, since several inhibitors can be held at once
+        self.ensure_within_rate(format_args!("add an inhibitor"))?;
+        self.ensure_not_read_only(format_args!("add an inhibitor"))?;
+        if self.sweep.is_some() {
+            // The current state is just a step of the sweep
+            return Err(busy_error());
+        }
+        let owner = self
+            .caller
+            .clone()
+            .ok_or_else(|| dbus::MethodErr::failed("unknown caller"))?;
+        if self.inhibitors.pinned.is_none() {
+            // Whatever happens in the meantime is applied once the fans are
+            // released
+            if self.unrequested.get().is_none() {
+                self.unrequested.set(Some(self.snapshot()?));
+            }
+            self.inhibitors.pinned = Some(self.read_snapshot()?);
+        }
+        let (ours, theirs) = pipe()
+            .map_err(|err| dbus::MethodErr::failed(&format!("couldn't create pipe: {err}")))?;
+        self.inhibitors.next_id += 1;
+        let id = self.inhibitors.next_id;
+        eprintln!("[info] inhibitor {id} from {owner} ({reason}) pins the fans");
+        self.inhibitors.inhibitors.push(Inhibitor {
+            id,
+            owner,
+            reason,
+            pipe: ours,
+        });
+        // SAFETY: the fd was just taken out of an OwnedFd, so it's open and
+        // has no other owner
+        Ok(unsafe { dbus::arg::OwnedFd::new(std::os::fd::IntoRawFd::into_raw_fd(theirs)) })
+    }
+
+    /// Releases the locks whose clients closed their file descriptors. Once
+    /// the last one is gone, requests and everything else get the fans
+    /// back.
+    pub(super) fn poll_inhibitors(&mut self) {
+        if self.inhibitors.reap() > 0 && self.inhibitors.inhibitors.is_empty() {
+            eprintln!("[info] no inhibitor is held anymore");
+            self.inhibitors.pinned = None;
+            self.inhibitors.overridden = false;
+            let _ = self.apply_requests();
+        }
+    }
+
+    /// Switches to gaming fans if it gets too hot while the fans are pinned,
+    /// and pins them again once it cools down. If the temperatures can't be
+    /// read, it might be too hot, so that switches to gaming fans too. This
+    /// is called every [`POLL_INTERVAL`].
+    pub(super) fn poll_safety(&mut self) -> Result<(), ec::EcError> {
+        let Some(pinned) = self.inhibitors.pinned else {
+            return Ok(());
+        };
+        let ec = self.ec.get_mut();
+        let temp = ec.temp_cpu().and_then(|temp_cpu| {
+            let temp_gpu = self.gpu_check.temp(ec.temp_gpu()?).temp_or_zero();
+            Ok(temp_cpu.max(temp_gpu))
+        });
+        if !self.inhibitors.overridden {
+            let why = match temp {
+                Ok(temp) if temp < self.safety_temp => return Ok(()),
+                Ok(temp) => format!("it's {temp}°C"),
+                Err(err) => format!("couldn't read the temperatures ({err})"),
+            };
+            eprintln!("[warn] {why}, switching to gaming fans despite the inhibitors");
+            ec.set_fan_modes(fan_mode_bits(FanMode::Gaming))?;
+            self.inhibitors.overridden = true;
+        } else if temp.is_ok_and(|temp| temp < self.safety_temp.saturating_sub(SAFETY_HYSTERESIS)) {
+            eprintln!("[info] cooled down, pinning the fans again");
+            self.inhibitors.overridden = false;
+            self.write_snapshot(pinned)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+//! Integration with [`power-profiles-daemon`][ppd], which is what desktop
+//! environments use to switch between "power saver", "balanced" and
+//! "performance".
+//!
+//! This works in both directions. When following, the server switches fan
+//! modes whenever the active power profile changes. When publishing, the
+//! server switches the active power profile whenever a client changes the fan
+//! mode. (Userspace can't register as a real platform profile driver, so
+//! setting the active profile is the closest we can get.)
+//!
+//! [ppd]: https://gitlab.freedesktop.org/upower/power-profiles-daemon
+use super::*;
+use dbus::blocking::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+use dbus::message::SignalArgs;
+
+const PPD_NAME: &str = "net.hadess.PowerProfiles";
+const PPD_PATH: &str = "/net/hadess/PowerProfiles";
+const PPD_INTERFACE: &str = "net.hadess.PowerProfiles";
+
+/// The configuration for `power-profiles-daemon` integration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerProfilesCfg {
+    /// Whether to switch fan modes when the active power profile changes.
+    pub follow: bool,
+
+    /// Whether to switch the active power profile when a client changes the
+    /// fan mode. Fan modes which no profile maps to are not published.
+    pub publish: bool,
+
+    /// The fan mode used for the `power-saver` profile.
+    #[serde(rename = "power-saver", deserialize_with = "de_fan_mode")]
+    pub power_saver: FanMode,
+
+    /// The fan mode used for the `balanced` profile.
+    #[serde(deserialize_with = "de_fan_mode")]
+    pub balanced: FanMode,
+
+    /// The fan mode used for the `performance` profile.
+    #[serde(deserialize_with = "de_fan_mode")]
+    pub performance: FanMode,
+}
+
+impl Default for PowerProfilesCfg {
+    fn default() -> Self {
+        Self {
+            follow: false,
+            publish: false,
+            power_saver: FanMode::Quiet,
+            balanced: FanMode::Normal,
+            performance: FanMode::Gaming,
+        }
+    }
+}
+
+impl PowerProfilesCfg {
+    /// Returns each profile and its corresponding fan mode.
+    fn mappings(&self) -> [(&'static str, FanMode); 3] {
+        [
+            ("power-saver", self.power_saver),
+            ("balanced", self.balanced),
+            ("performance", self.performance),
+        ]
+    }
+
+    /// Returns the fan mode for the given power profile, or [`None`] if the
+    /// profile is unrecognized.
+    pub fn mode_for(&self, profile: &str) -> Option<FanMode> {
+        self.mappings()
+            .into_iter()
+            .find_map(|(name, fan_mode)| (name == profile).then_some(fan_mode))
+    }
+
+    /// Returns the first power profile which maps to `fan_mode`, if any.
+    pub fn profile_for(&self, fan_mode: FanMode) -> Option<&'static str> {
+        self.mappings()
+            .into_iter()
+            .find_map(|(name, mapped)| (mapped == fan_mode).then_some(name))
+    }
+}
+
+/// Applies the currently active power profile, then keeps applying it
+/// whenever it changes.
+pub(super) fn watch(cxn: &Connection, cr: &Arc<Mutex<Crossroads>>) -> Result<(), dbus::Error> {
+    let rule =
+        PropertiesPropertiesChanged::match_rule(Some(&PPD_NAME.into()), Some(&PPD_PATH.into()))
+            .static_clone();
+    let cr_match = Arc::clone(cr);
+    cxn.add_match(rule, move |changed: PropertiesPropertiesChanged, _, _| {
+        if changed.interface_name == PPD_INTERFACE {
+            let profile = changed
+                .changed_properties
+                .get("ActiveProfile")
+                .and_then(|profile| profile.0.as_str());
+            if let Some(profile) = profile {
+                with_controller(&cr_match, |c| c.power_profile_changed(profile));
+            }
+        }
+        true
+    })?;
+
+    let proxy = cxn.with_proxy(PPD_NAME, PPD_PATH, Duration::from_millis(1000));
+    let profile: String = proxy.get(PPD_INTERFACE, "ActiveProfile")?;
+    with_controller(cr, |c| c.power_profile_changed(&profile));
+    Ok(())
+}
+
+/// Sets the active power profile.
+pub(super) fn publish(cxn: &Connection, profile: &str) -> Result<(), dbus::Error> {
+    let proxy = cxn.with_proxy(PPD_NAME, PPD_PATH, Duration::from_millis(1000));
+    proxy.set(PPD_INTERFACE, "ActiveProfile", profile.to_string())
+}
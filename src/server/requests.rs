@@ -0,0 +1,197 @@
+//! Arbitration between clients which want the fans in different states.
+//! Instead of setting the fans directly (and fighting over them), clients
+//! add requests with a priority. The highest-priority request is in effect,
+//! and each request goes away once the client which made it leaves the bus,
+//! like an inhibitor lock.
+use super::*;
+
+/// A client's request for a fan state.
+#[derive(Debug, Clone)]
+pub(super) struct FanRequest {
+    pub id: u32,
+    /// The unique bus name of the client which made the request.
+    pub owner: String,
+    pub fan_mode: FanMode,
+    /// The fixed fan speed (0.0..=1.0). Only used with [`FanMode::Fixed`].
+    pub fixed_fan_speed: f64,
+    pub priority: i32,
+    /// Why the client wants this, for people wondering why their fans are
+    /// stuck.
+    pub reason: String,
+}
+
+/// Every active request.
+#[derive(Default)]
+pub(super) struct Requests {
+    requests: Vec<FanRequest>,
+    next_id: u32,
+}
+
+impl Requests {
+    /// Adds a request, returning its ID.
+    fn add(&mut self, request: FanRequest) -> u32 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.requests.push(FanRequest { id, ..request });
+        id
+    }
+
+    /// Returns the requests, starting with the one in effect. Higher
+    /// priorities win, then newer requests.
+    pub fn sorted(&self) -> Vec<&FanRequest> {
+        let mut sorted: Vec<_> = self.requests.iter().collect();
+        sorted.sort_by_key(|request| std::cmp::Reverse((request.priority, request.id)));
+        sorted
+    }
+
+    /// Returns the request in effect, if any.
+    pub fn effective(&self) -> Option<&FanRequest> {
+        self.sorted().into_iter().next()
+    }
+}
+
+/// Returns the error for attempting to change the fans directly while a
+/// request is in effect.
+fn requested_error(request: &FanRequest) -> dbus::MethodErr {
+    (
+        "com.offbyond.a15kb.Error.Requested",
+        format!(
+            "the fans are held by request {} from {} ({})",
+            request.id, request.owner, request.reason
+        ),
+    )
+        .into()
+}
+
+impl Controller {
+    /// Fails if a request is in effect, since the fans can then only be
+    /// changed through requests. `what` describes the change, for the log.
+    pub(super) fn ensure_not_requested(
+        &self,
+        what: std::fmt::Arguments,
+    ) -> Result<(), dbus::MethodErr> {
+        match self.requests.effective() {
+            Some(request) => {
+                eprintln!(
+                    "[info] request {} is in effect, refusing to {what}",
+                    request.id
+                );
+                Err(requested_error(request))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Adds a request from the client whose call is being handled.
+    pub(super) fn add_request(
+        &mut self,
+        fan_mode: FanMode,
+        fixed_fan_speed: f64,
+        priority: i32,
+        reason: String,
+    ) -> Result<u32, dbus::MethodErr> {
+        if let FanMode::Unknown { .. } = fan_mode {
+            return Err(dbus::MethodErr::invalid_arg(&fan_mode.to_string()));
+        }
+        let allowed = ec::FAN_FIXED_SPEED_MIN..=ec::FAN_FIXED_SPEED_MAX;
+        if fan_mode == FanMode::Fixed && !allowed.contains(&fixed_fan_speed) {
+            return Err(dbus::MethodErr::invalid_arg(&fixed_fan_speed));
+        }
+        self.ensure_can_write(format_args!("add a request for {fan_mode} fans"))?;
+        let owner = self
+            .caller
+            .clone()
+            .ok_or_else(|| dbus::MethodErr::failed("unknown caller"))?;
+        let id = self.requests.add(FanRequest {
+            id: 0,
+            owner,
+            fan_mode,
+            fixed_fan_speed,
+            priority,
+            reason,
+        });
+        self.apply_requests()?;
+        Ok(id)
+    }
+
+    /// Releases a request made by the client whose call is being handled.
+    pub(super) fn release_request(&mut self, id: u32) -> Result<(), dbus::MethodErr> {
+        let Some(index) = self.requests.requests.iter().position(|r| r.id == id) else {
+            return Err(dbus::MethodErr::invalid_arg(&id));
+        };
+        if self.caller.as_ref() != Some(&self.requests.requests[index].owner) {
+            return Err((
+                "org.freedesktop.DBus.Error.AccessDenied",
+                "only the client which made a request can release it",
+            )
+                .into());
+        }
+        self.requests.requests.remove(index);
+        eprintln!("[info] request {id} released");
+        self.apply_requests()?;
+        Ok(())
+    }
+
+    /// Releases every request made by `owner`, which just left the bus, and
+    /// forgets its rate limit.
+    fn owner_gone(&mut self, owner: &str) {
+        self.rate_limiter.forget(owner);
+        let before = self.requests.requests.len();
+        self.requests
+            .requests
+            .retain(|request| request.owner != owner);
+        let released = before - self.requests.requests.len();
+        if released > 0 {
+            eprintln!("[info] {owner} left, released its {released} request(s)");
+            let _ = self.apply_requests();
+        }
+    }
+
+    /// Puts the fans in the state of the request in effect. If there isn't
+    /// one anymore, the state from before the first request is restored,
+    /// along with any changes made in the meantime. While an inhibitor is
+    /// held, this does nothing; it's called again once they're all gone.
+    pub(super) fn apply_requests(&mut self) -> Result<(), ec::EcError> {
+        if self.inhibitors.is_held() {
+            return Ok(());
+        }
+        let Some(request) = self.requests.effective().cloned() else {
+            if let Some(unrequested) = self.unrequested.take() {
+                eprintln!("[info] no request is in effect anymore");
+                self.restore(unrequested)?;
+            }
+            return Ok(());
+        };
+        if self.unrequested.get().is_none() {
+            self.unrequested.set(Some(self.snapshot()?));
+        }
+        eprintln!(
+            "[info] request {} from {} ({}) is in effect, switching to {} fans",
+            request.id, request.owner, request.reason, request.fan_mode
+        );
+        let mut ec = self.ec.borrow_mut();
+        if request.fan_mode == FanMode::Fixed {
+            let hw_speed = fixed_hw_speed(request.fixed_fan_speed);
+            ec.set_fan_fixed_hw_speeds((hw_speed, hw_speed))?;
+        }
+        ec.set_fan_modes(fan_mode_bits(request.fan_mode))
+    }
+}
+
+/// Releases clients' requests whenever they leave the bus.
+pub(super) fn watch(cxn: &Connection, cr: &Arc<Mutex<Crossroads>>) -> Result<(), dbus::Error> {
+    let rule = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+        .with_sender("org.freedesktop.DBus");
+    let cr_match = Arc::clone(cr);
+    cxn.add_match(
+        rule,
+        move |(name, _, new_owner): (String, String, String), _, _| {
+            // Requests are owned by unique names, which are never reused
+            if name.starts_with(':') && new_owner.is_empty() {
+                with_controller(&cr_match, |c| c.owner_gone(&name));
+            }
+            true
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,194 @@
+//! Per-application fan rules. The server periodically scans `/proc`, and
+//! whenever a process matching a rule is running, it switches to that rule's
+//! fan settings.
+use super::*;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::time::Instant;
+
+/// How often to scan for running processes.
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A single per-application rule.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleCfg {
+    /// A human-readable name for the rule.
+    pub name: String,
+
+    /// Rules with a higher priority win over rules with a lower priority.
+    #[serde(default)]
+    pub priority: i32,
+
+    /// Matches processes whose executable has this file name, e.g. `cargo`.
+    /// Once the server has switched users (`--user`), it can't read other
+    /// users' executables anymore, so their processes are matched by the
+    /// file name they were started as (`argv[0]`) instead.
+    pub exe: Option<String>,
+
+    /// Matches processes whose cgroup path contains this string, e.g.
+    /// `app-steam`.
+    pub cgroup: Option<String>,
+
+    /// The fan mode to switch to.
+    #[serde(rename = "mode", deserialize_with = "de_fan_mode")]
+    pub fan_mode: FanMode,
+
+    /// The fixed fan speed to switch to, if any.
+    pub fixed_speed: Option<f64>,
+}
+
+impl RuleCfg {
+    /// Checks that the rule can actually be applied.
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(
+            self.exe.is_some() || self.cgroup.is_some(),
+            "rule \"{}\" doesn't match anything (needs `exe` or `cgroup`)",
+            self.name
+        );
+        if let Some(speed) = self.fixed_speed {
+            anyhow::ensure!(
+                (ec::FAN_FIXED_SPEED_MIN..=ec::FAN_FIXED_SPEED_MAX).contains(&speed),
+                "rule \"{}\" has an out-of-range fixed speed",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns whether the process described by `exe` and `cgroup` matches
+    /// this rule. The cgroup is only read if the rule cares about it.
+    fn matches(&self, exe: Option<&str>, cgroup: &mut impl FnMut() -> Option<String>) -> bool {
+        if let Some(wanted) = &self.exe {
+            if exe != Some(wanted.as_str()) {
+                return false;
+            }
+        }
+        if let Some(wanted) = &self.cgroup {
+            if !cgroup().is_some_and(|cgroup| cgroup.contains(wanted.as_str())) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Keeps track of which rule (if any) is currently in effect.
+pub(super) struct RuleEngine {
+    /// The rules, sorted from highest to lowest priority.
+    rules: Vec<RuleCfg>,
+    /// The index of the rule currently in effect.
+    active: Option<usize>,
+    /// When `/proc` was last scanned.
+    last_scan: Option<Instant>,
+    /// Whether we already said that some executables can't be read.
+    exe_warned: Cell<bool>,
+}
+
+impl RuleEngine {
+    /// Creates a new rule engine, failing if any rule is invalid.
+    pub fn new(rules: &[RuleCfg]) -> Result<Self, anyhow::Error> {
+        for rule in rules {
+            rule.validate()?;
+        }
+        let mut rules = rules.to_vec();
+        // (Stable, so equal priorities keep the order they were written in)
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+        Ok(Self {
+            rules,
+            active: None,
+            last_scan: None,
+            exe_warned: Cell::new(false),
+        })
+    }
+
+    /// Returns every rule, from highest to lowest priority.
+    pub fn rules(&self) -> &[RuleCfg] {
+        &self.rules
+    }
+
+    /// Returns the rule currently in effect.
+    pub fn active(&self) -> Option<&RuleCfg> {
+        self.active.map(|idx| &self.rules[idx])
+    }
+
+    /// Rescans running processes if it's been long enough since the last
+    /// scan. Returns `true` if the active rule changed.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        if self
+            .last_scan
+            .is_some_and(|last_scan| now.duration_since(last_scan) < SCAN_INTERVAL)
+        {
+            return false;
+        }
+        self.last_scan = Some(now);
+        let active = self.scan();
+        let changed = active != self.active;
+        self.active = active;
+        changed
+    }
+
+    /// Returns the index of the highest-priority rule matching any running
+    /// process.
+    fn scan(&self) -> Option<usize> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            eprintln!("[warn] couldn't scan /proc");
+            return None;
+        };
+        let mut best: Option<usize> = None;
+        for entry in entries.flatten() {
+            let proc_dir = entry.path();
+            let is_pid = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()));
+            if !is_pid {
+                continue;
+            }
+            let Some(exe) = self.exe_name(&proc_dir) else {
+                continue;
+            };
+            let exe = Some(exe.as_str());
+            let mut cgroup = || fs::read_to_string(proc_dir.join("cgroup")).ok();
+            // Only rules with a higher priority than the current best matter.
+            let candidates = best.unwrap_or(self.rules.len());
+            if let Some(idx) = self.rules[..candidates]
+                .iter()
+                .position(|rule| rule.matches(exe, &mut cgroup))
+            {
+                best = Some(idx);
+                if idx == 0 {
+                    break;
+                }
+            }
+        }
+        best
+    }
+
+    /// Returns the file name of the executable running in `proc_dir`, or
+    /// `None` for kernel threads and processes which already exited.
+    fn exe_name(&self, proc_dir: &Path) -> Option<String> {
+        let name = |path: &Path| path.file_name()?.to_str().map(str::to_string);
+        match fs::read_link(proc_dir.join("exe")) {
+            Ok(exe) => name(&exe),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            // Other users' processes, once we've switched users. Their
+            // command lines are still readable, though.
+            Err(err) => {
+                if !self.exe_warned.replace(true) {
+                    eprintln!(
+                        "[info] couldn't read {}/exe ({err}), matching by command line instead",
+                        proc_dir.display()
+                    );
+                }
+                let cmdline = fs::read(proc_dir.join("cmdline")).ok()?;
+                let argv0 = cmdline.split(|&byte| byte == 0).next()?;
+                name(Path::new(OsStr::from_bytes(argv0)))
+            }
+        }
+    }
+}
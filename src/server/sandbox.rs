@@ -0,0 +1,213 @@
+//! Giving up privileges once they're no longer needed. The server only
+//! needs root to load `ec_sys` and open the embedded controller; after that,
+//! the open file handle is all it needs to control the fans.
+use super::*;
+use std::ffi::CString;
+use std::io;
+
+/// How the server sandboxes itself once the embedded controller is open.
+#[derive(Debug, Clone, Default)]
+pub struct SandboxCfg {
+    /// The user to switch to. The state directory is handed over to them.
+    pub user: Option<String>,
+
+    /// Whether to forbid system calls the server never needs (loading
+    /// kernel modules, mounting, tracing, running programs, ...).
+    pub seccomp: bool,
+}
+
+/// Applies the sandbox configured by `cfg`.
+pub(super) fn apply(cfg: &SandboxCfg, state_dir: &Path) -> Result<(), anyhow::Error> {
+    if cfg.user.is_none() && !cfg.seccomp {
+        return Ok(());
+    }
+    // Nothing we run from now on gets to gain privileges back, e.g. through
+    // setuid binaries. This is also what lets us install a seccomp filter
+    // without being root.
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })
+        .context("couldn't set no_new_privs")?;
+    if let Some(user) = &cfg.user {
+        switch_user(user, state_dir).with_context(|| format!("couldn't switch to user {user}"))?;
+        eprintln!("[info] switched to user {user}");
+    }
+    if cfg.seccomp {
+        install_seccomp_filter().context("couldn't install seccomp filter")?;
+        eprintln!("[info] seccomp filter installed");
+    }
+    Ok(())
+}
+
+/// Turns a libc return value into an error if it's negative.
+fn check(ret: libc::c_int) -> io::Result<()> {
+    match ret {
+        ..0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Looks up the user and primary group IDs of `user`.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), anyhow::Error> {
+    let name = CString::new(user).context("invalid user name")?;
+    let mut buffer = vec![0; 16 * 1024];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let err = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err)).context("couldn't look up user");
+    }
+    anyhow::ensure!(!result.is_null(), "no such user");
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Switches to `user`, handing the state directory over to them first.
+fn switch_user(user: &str, state_dir: &Path) -> Result<(), anyhow::Error> {
+    let (uid, gid) = lookup_user(user)?;
+    if unsafe { libc::getuid() } == uid {
+        return Ok(());
+    }
+
+    // The calibration table may have been written while we were still
+    // running as root
+    std::fs::create_dir_all(state_dir).context("couldn't create state directory")?;
+    let chown = |path: &Path| {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))
+            .with_context(|| format!("couldn't hand {} over", path.display()))
+    };
+    chown(state_dir)?;
+    for entry in std::fs::read_dir(state_dir).context("couldn't read state directory")? {
+        chown(&entry.context("couldn't read state directory")?.path())?;
+    }
+
+    // Changing the user ID away from root also clears every capability, so
+    // there's nothing else to drop
+    check(unsafe { libc::setgroups(1, &gid) }).context("couldn't set groups")?;
+    check(unsafe { libc::setgid(gid) }).context("couldn't set group")?;
+    check(unsafe { libc::setuid(uid) }).context("couldn't set user")?;
+    Ok(())
+}
+
+/// The architecture the filter is written for, as seen by seccomp
+/// (`AUDIT_ARCH_X86_64`).
+const AUDIT_ARCH: u32 = 0xc000_003e;
+
+/// System calls which the server never needs once it's running. Anything
+/// else is allowed, since the D-Bus library's needs change between versions.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    // Running programs
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    // Kernel modules
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    // Getting privileges back
+    libc::SYS_setuid,
+    libc::SYS_setgid,
+    libc::SYS_setreuid,
+    libc::SYS_setregid,
+    libc::SYS_setresuid,
+    libc::SYS_setresgid,
+    libc::SYS_setgroups,
+    // Escaping or changing the file system
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    // Poking at other processes or the kernel
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_iopl,
+    libc::SYS_ioperm,
+    // Changing the system
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_clock_adjtime,
+    libc::SYS_adjtimex,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+];
+
+/// Installs a seccomp filter which makes [`DENIED_SYSCALLS`] fail with
+/// `EPERM`, on every thread.
+fn install_seccomp_filter() -> Result<(), anyhow::Error> {
+    anyhow::ensure!(
+        cfg!(target_arch = "x86_64"),
+        "the seccomp filter only supports x86_64"
+    );
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code: u32, k: u32, jt: usize, jf: usize| libc::sock_filter {
+        code: code as u16,
+        jt: jt as u8,
+        jf: jf as u8,
+        k,
+    };
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+    let jge = libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K;
+    let ret = libc::BPF_RET | libc::BPF_K;
+    let denied = DENIED_SYSCALLS.len();
+
+    // struct seccomp_data starts with the syscall number, then the
+    // architecture
+    let mut program = vec![
+        stmt(load, 4),
+        jump(jeq, AUDIT_ARCH, 1, 0),
+        stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(load, 0),
+        // x32 system calls have their own numbers, and we don't use them
+        jump(jge, 0x4000_0000, denied + 1, 0),
+    ];
+    for (i, &syscall) in DENIED_SYSCALLS.iter().enumerate() {
+        program.push(jump(jeq, syscall as u32, denied - i, 0));
+    }
+    program.push(stmt(ret, libc::SECCOMP_RET_ALLOW));
+    program.push(stmt(ret, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog,
+        )
+    };
+    // With TSYNC, a positive return value is a thread which couldn't be
+    // synchronized
+    anyhow::ensure!(ret == 0, "seccomp failed: {}", io::Error::last_os_error());
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Time-of-day fan schedules, e.g. quiet fans from 22:00 to 07:00.
+//!
+//! A schedule is a list of weekly switch points. At each switch point, the
+//! server switches to that entry's fan mode (and fixed speed, if given), so
+//! "quiet from 22:00 to 07:00" is written as two entries: quiet at 22:00 and
+//! normal at 07:00. Since the server only acts at switch points, any manual
+//! change lasts until the next one.
+use super::*;
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Weekday};
+
+/// A single switch point in the schedule.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntryCfg {
+    /// The local time of day to switch at, e.g. `22:00`.
+    #[serde(deserialize_with = "de_time")]
+    pub at: NaiveTime,
+
+    /// The days of the week to switch on. Defaults to every day.
+    #[serde(default = "every_day", deserialize_with = "de_days")]
+    pub days: Vec<Weekday>,
+
+    /// The fan mode to switch to.
+    #[serde(rename = "mode", deserialize_with = "de_fan_mode")]
+    pub fan_mode: FanMode,
+
+    /// The fixed fan speed to switch to, if any.
+    pub fixed_speed: Option<f64>,
+}
+
+fn every_day() -> Vec<Weekday> {
+    use Weekday::*;
+    vec![Mon, Tue, Wed, Thu, Fri, Sat, Sun]
+}
+
+/// Deserializes a time of day written as `HH:MM`.
+fn de_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .map_err(|_| serde::de::Error::custom(format!("invalid time \"{time}\" (expected HH:MM)")))
+}
+
+/// Deserializes a list of weekday names, e.g. `["mon", "tue"]`.
+fn de_days<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Weekday>, D::Error> {
+    let days = Vec::<String>::deserialize(deserializer)?;
+    days.iter()
+        .map(|day| {
+            day.parse()
+                .map_err(|_| serde::de::Error::custom(format!("unknown weekday \"{day}\"")))
+        })
+        .collect()
+}
+
+/// Keeps track of the schedule's next switch point.
+pub(super) struct Schedule {
+    entries: Vec<ScheduleEntryCfg>,
+    /// Whether the schedule has been polled yet.
+    started: bool,
+    /// The next switch point, if there is one.
+    next: Option<DateTime<Local>>,
+}
+
+impl Schedule {
+    /// Creates a new schedule, failing if any entry is invalid.
+    pub fn new(entries: &[ScheduleEntryCfg]) -> Result<Self, anyhow::Error> {
+        for entry in entries {
+            if let Some(speed) = entry.fixed_speed {
+                anyhow::ensure!(
+                    (ec::FAN_FIXED_SPEED_MIN..=ec::FAN_FIXED_SPEED_MAX).contains(&speed),
+                    "schedule entry at {} has an out-of-range fixed speed",
+                    entry.at
+                );
+            }
+        }
+        Ok(Self {
+            entries: entries.to_vec(),
+            started: false,
+            next: None,
+        })
+    }
+
+    /// Returns whether the schedule has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns every switch point within a week of `now`, in no particular
+    /// order.
+    fn switch_points(
+        &self,
+        now: DateTime<Local>,
+    ) -> impl Iterator<Item = (DateTime<Local>, &ScheduleEntryCfg)> {
+        let today = now.date_naive();
+        (-7..=7)
+            .filter_map(move |offset| today.checked_add_signed(chrono::Duration::days(offset)))
+            .flat_map(move |date| {
+                self.entries
+                    .iter()
+                    .filter(move |entry| entry.days.contains(&date.weekday()))
+                    .filter_map(move |entry| {
+                        // Switch points which fall into a DST gap are skipped.
+                        let at = Local.from_local_datetime(&date.and_time(entry.at));
+                        Some((at.earliest()?, entry))
+                    })
+            })
+    }
+
+    /// Returns the entry in effect at `now`, i.e. the one with the latest
+    /// switch point at or before `now`.
+    pub fn current(&self, now: DateTime<Local>) -> Option<&ScheduleEntryCfg> {
+        self.switch_points(now)
+            .filter(|(at, _)| *at <= now)
+            .max_by_key(|(at, _)| *at)
+            .map(|(_, entry)| entry)
+    }
+
+    /// Returns the first switch point after `now`.
+    pub fn next_after(&self, now: DateTime<Local>) -> Option<(DateTime<Local>, &ScheduleEntryCfg)> {
+        self.switch_points(now)
+            .filter(|(at, _)| *at > now)
+            .min_by_key(|(at, _)| *at)
+    }
+
+    /// Returns the entry which should be applied now, if any. This is the
+    /// current entry the first time the schedule is polled, and afterwards
+    /// whenever a switch point has passed.
+    pub fn poll(&mut self, now: DateTime<Local>) -> Option<&ScheduleEntryCfg> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        if self.started && self.next.is_none_or(|next| now < next) {
+            return None;
+        }
+        self.started = true;
+        self.next = self.next_after(now).map(|(at, _)| at);
+        // If we were suspended, we could've slept through several switch
+        // points, so look up what's in effect now instead of assuming it's
+        // the one we were waiting for.
+        self.current(now)
+    }
+}
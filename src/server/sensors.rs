@@ -0,0 +1,145 @@
+//! Temperature (and other) sensors. The CPU and GPU temperatures come from
+//! the embedded controller and are always there; anything else is
+//! configured, and can come from another EC offset or from a kernel hwmon
+//! device, e.g. the NVMe drive.
+use super::*;
+use std::fs;
+
+/// Where hwmon devices live.
+const HWMON_DIR: &str = "/sys/class/hwmon";
+
+/// A configured sensor. Exactly one of `ec_offset` and `hwmon` must be set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorCfg {
+    /// The sensor's name, e.g. `nvme`. Must be unique.
+    pub name: String,
+
+    /// The EC offset of a byte holding a temperature in Celcius.
+    pub ec_offset: Option<u8>,
+
+    /// The name of a hwmon device, as found in `/sys/class/hwmon/*/name`.
+    pub hwmon: Option<String>,
+
+    /// Which of the hwmon device's inputs to read, e.g. `temp2` or `fan1`.
+    /// Defaults to `temp1`.
+    pub input: Option<String>,
+}
+
+/// Where a sensor's value comes from.
+#[derive(Debug, Clone)]
+enum Source {
+    Ec(u8),
+    Hwmon { device: String, input: String },
+}
+
+impl Source {
+    /// Returns the unit of the sensor's values, along with how much to
+    /// divide the raw values by to get there.
+    fn unit(&self) -> (&'static str, f64) {
+        let Self::Hwmon { input, .. } = self else {
+            return ("celsius", 1.0);
+        };
+        // See the kernel's Documentation/hwmon/sysfs-interface.rst
+        let kind = input.trim_end_matches(|c: char| c.is_ascii_digit());
+        match kind {
+            "temp" => ("celsius", 1000.0),
+            "fan" => ("rpm", 1.0),
+            "in" => ("volts", 1000.0),
+            "curr" => ("amps", 1000.0),
+            "power" => ("watts", 1_000_000.0),
+            _ => ("", 1.0),
+        }
+    }
+
+    /// Finds the sysfs file to read a hwmon input from, if the device is
+    /// there. Device numbers aren't stable across boots (or hotplugs), so
+    /// this searches by name every time.
+    fn hwmon_path(device: &str, input: &str) -> Option<PathBuf> {
+        fs::read_dir(HWMON_DIR).ok()?.flatten().find_map(|entry| {
+            let name = fs::read_to_string(entry.path().join("name")).ok()?;
+            (name.trim() == device).then(|| entry.path().join(format!("{input}_input")))
+        })
+    }
+}
+
+/// A single reading from a sensor.
+pub(super) struct Reading<'a> {
+    pub name: &'a str,
+    /// The EC offset (e.g. `ec:0x60`) or sysfs path the value came from.
+    pub source: String,
+    pub unit: &'static str,
+    /// This is [`None`] if the sensor couldn't be read.
+    pub value: Option<f64>,
+}
+
+/// The list of sensors.
+pub(super) struct Sensors {
+    sensors: Vec<(String, Source)>,
+}
+
+impl Sensors {
+    /// Creates the sensor list from the built-in sensors and the configured
+    /// ones, failing if any configured sensor is invalid.
+    pub fn new(cfg: &[SensorCfg]) -> Result<Self, anyhow::Error> {
+        let mut sensors = vec![
+            ("cpu".to_string(), Source::Ec(ec::TEMP_CPU_OFFSET)),
+            ("gpu".to_string(), Source::Ec(ec::TEMP_GPU_OFFSET)),
+        ];
+        for sensor in cfg {
+            anyhow::ensure!(
+                sensors.iter().all(|(name, _)| *name != sensor.name),
+                "there's more than one sensor named \"{}\"",
+                sensor.name
+            );
+            let source = match (sensor.ec_offset, &sensor.hwmon) {
+                (Some(offset), None) => Source::Ec(offset),
+                (None, Some(device)) => Source::Hwmon {
+                    device: device.clone(),
+                    input: sensor.input.clone().unwrap_or_else(|| "temp1".to_string()),
+                },
+                _ => anyhow::bail!(
+                    "sensor \"{}\" needs exactly one of ec_offset and hwmon",
+                    sensor.name
+                ),
+            };
+            sensors.push((sensor.name.clone(), source));
+        }
+        Ok(Self { sensors })
+    }
+
+    /// Returns the names of the sensors.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sensors.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Reads every sensor.
+    pub fn read(&self, ec: &mut ec::Ec) -> Vec<Reading<'_>> {
+        self.sensors
+            .iter()
+            .map(|(name, source)| {
+                let (unit, divisor) = source.unit();
+                let (source, raw) = match source {
+                    Source::Ec(offset) => {
+                        let raw = ec.temp_at(*offset).ok().map(f64::from);
+                        (format!("ec:{offset:#04x}"), raw)
+                    }
+                    Source::Hwmon { device, input } => match Source::hwmon_path(device, input) {
+                        Some(path) => {
+                            let raw = fs::read_to_string(&path).ok();
+                            let raw = raw.and_then(|raw| raw.trim().parse::<f64>().ok());
+                            (path.display().to_string(), raw)
+                        }
+                        None => (format!("hwmon:{device}/{input}"), None),
+                    },
+                };
+                Reading {
+                    name,
+                    source,
+                    unit,
+                    value: raw.map(|raw| raw / divisor),
+                }
+            })
+            .collect()
+    }
+}
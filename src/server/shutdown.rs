@@ -0,0 +1,73 @@
+//! Stopping the server cleanly: SIGTERM and SIGINT end the main loop, and the
+//! fans are left in whatever state the configuration asks for.
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What to do with the fans when the server stops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownPolicy {
+    /// Leave the fans as they are.
+    #[default]
+    Keep,
+    /// Put the fans back the way they were when the server started.
+    Restore,
+    /// Switch the fans to [`FanMode::Normal`].
+    Normal,
+}
+
+/// Set once SIGTERM or SIGINT arrives.
+pub(super) struct StopFlag(Arc<AtomicBool>);
+
+impl StopFlag {
+    /// Installs the signal handlers. A second signal kills the server
+    /// outright, in case it's stuck.
+    pub fn install() -> Result<Self, anyhow::Error> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        let flag = Arc::new(AtomicBool::new(false));
+        for signal in [SIGTERM, SIGINT] {
+            signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&flag))
+                .context("couldn't install signal handler")?;
+            signal_hook::flag::register(signal, Arc::clone(&flag))
+                .context("couldn't install signal handler")?;
+        }
+        Ok(Self(flag))
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Controller {
+    /// Leaves the fans as `policy` says. A running sweep is always cut short
+    /// and the state from before it restored, since its speeds are
+    /// meaningless on their own. A request in effect counts as the state the
+    /// fans are in.
+    pub(super) fn shut_down(&mut self, policy: ShutdownPolicy) -> Result<(), ec::EcError> {
+        // Requests don't outlive the server, so write to the fans from now on
+        self.unrequested.take();
+        self.flush_fixed_fan_speed(true)?;
+        if let Some(sweep) = self.sweep.take() {
+            eprintln!("[info] aborting {:?} sweep", sweep.purpose());
+            self.restore(sweep.previous())?;
+        }
+        match (policy, self.startup) {
+            (ShutdownPolicy::Keep, _) => Ok(()),
+            (ShutdownPolicy::Restore, Some(startup)) => {
+                eprintln!("[info] restoring the fan state from startup");
+                self.restore(startup)
+            }
+            (ShutdownPolicy::Restore, None) => {
+                eprintln!(
+                    "[warn] the fan state from startup is unknown, leaving the fans as they are"
+                );
+                Ok(())
+            }
+            (ShutdownPolicy::Normal, _) => {
+                eprintln!("[info] switching to normal fans");
+                self.write_fan_mode(FanMode::Normal)
+            }
+        }
+    }
+}
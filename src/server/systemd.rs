@@ -0,0 +1,25 @@
+//! Just enough of the `sd_notify` protocol to tell systemd when the server
+//! is ready and when it's stopping (`Type=notify` in the unit file).
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Sends `state` (e.g. `READY=1`) to systemd. Does nothing if the server
+/// wasn't started by systemd.
+pub(super) fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let send = || -> std::io::Result<()> {
+        // Names starting with @ are in the abstract namespace
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    };
+    if let Err(err) = send() {
+        eprintln!("[warn] couldn't notify systemd: {err}");
+    }
+}
@@ -0,0 +1,93 @@
+//! Detection of other software (or firmware!) changing the fan registers
+//! behind our back. The embedded controller remembers what we last wrote, so
+//! anything different that shows up in the registers came from someone else.
+use super::*;
+
+/// What to do when the fan registers are changed behind our back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalChangePolicy {
+    /// Just report the change and leave the fans alone.
+    #[default]
+    Report,
+    /// Report the change, then change the fans back to what we last set.
+    Reassert,
+}
+
+/// The raw contents of the fan registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct FanRegisters {
+    /// `(quiet, gaming, fixed)`, as returned by [`ec::Ec::fan_modes`].
+    pub fan_modes: (bool, bool, bool),
+    pub fixed_hw_speeds: (u8, u8),
+}
+
+impl FanRegisters {
+    /// Reads the fan registers.
+    pub fn read(ec: &mut ec::Ec) -> Result<Self, ec::EcError> {
+        Ok(Self {
+            fan_modes: ec.fan_modes()?,
+            fixed_hw_speeds: ec.fan_fixed_hw_speeds()?,
+        })
+    }
+
+    /// Returns what we last wrote to the fan registers.
+    pub fn written(ec: &ec::Ec) -> Self {
+        Self {
+            fan_modes: ec.written_fan_modes(),
+            fixed_hw_speeds: ec.written_fan_fixed_hw_speeds(),
+        }
+    }
+
+    /// Writes these values back to the fan registers. Invalid fan mode bits
+    /// (which we may have found at startup) can't be written, so they're
+    /// written as [`FanMode::Normal`] instead.
+    pub fn write(&self, ec: &mut ec::Ec) -> Result<(), ec::EcError> {
+        ec.set_fan_fixed_hw_speeds(self.fixed_hw_speeds)?;
+        let fan_mode = match self.fan_mode() {
+            FanMode::Unknown { .. } => FanMode::Normal,
+            fan_mode => fan_mode,
+        };
+        ec.set_fan_modes(fan_mode_bits(fan_mode))
+    }
+
+    /// Returns the fan mode.
+    pub fn fan_mode(&self) -> FanMode {
+        decode_fan_modes(self.fan_modes)
+    }
+
+    /// Returns the fixed fan speed (0.0..=1.0).
+    pub fn fixed_fan_speed(&self) -> f64 {
+        decode_fixed_hw_speeds(self.fixed_hw_speeds)
+    }
+}
+
+/// Keeps track of which external changes have already been reported.
+#[derive(Default)]
+pub(super) struct TamperDetector {
+    /// The registers as of the last reported change, if they still haven't
+    /// gone back to what we wrote.
+    reported: Option<FanRegisters>,
+}
+
+impl TamperDetector {
+    /// Compares the fan registers with what we last wrote. Returns what we
+    /// wrote and what's there now if they differ, unless that difference
+    /// was already reported.
+    pub fn check(
+        &mut self,
+        ec: &mut ec::Ec,
+    ) -> Result<Option<(FanRegisters, FanRegisters)>, ec::EcError> {
+        let written = FanRegisters::written(ec);
+        let actual = FanRegisters::read(ec)?;
+        if actual == written {
+            self.reported = None;
+            return Ok(None);
+        }
+        if self.reported == Some(actual) {
+            return Ok(None);
+        }
+        self.reported = Some(actual);
+        Ok(Some((written, actual)))
+    }
+}
@@ -0,0 +1,205 @@
+//! Keeps clients from hammering the embedded controller (and the server).
+//! Fixed fan speed changes are coalesced, so dragging a slider ends up as a
+//! handful of writes of the latest value instead of one per pixel, and each
+//! client can only make so many calls which change the fans.
+use super::*;
+use std::collections::HashMap;
+
+/// How often fixed fan speed changes from clients are written, at most.
+const WRITE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many calls which change the fans each client can make.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitCfg {
+    /// How many calls a client can make in a burst. The default is enough
+    /// to drag a slider across its whole range.
+    pub burst: u32,
+
+    /// How many calls per second a client can keep making once its burst is
+    /// used up.
+    pub per_second: f64,
+}
+
+impl Default for RateLimitCfg {
+    fn default() -> Self {
+        Self {
+            burst: 200,
+            per_second: 100.0,
+        }
+    }
+}
+
+/// A client's budget of calls, as a token bucket.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// How many calls were refused since the last one was allowed.
+    refused: u64,
+}
+
+/// Per-client rate limits.
+pub(super) struct RateLimiter {
+    cfg: RateLimitCfg,
+    buckets: RefCell<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: RateLimitCfg) -> Self {
+        Self {
+            cfg,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a call by `sender` out of its budget. Returns how many calls in
+    /// a row have been refused (including this one), or 0 if it's allowed.
+    fn take(&self, sender: &str, now: Instant) -> u64 {
+        let burst = f64::from(self.cfg.burst);
+        let mut buckets = self.buckets.borrow_mut();
+        let bucket = buckets.entry(sender.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            refused: 0,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.cfg.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.refused = 0;
+        } else {
+            bucket.refused += 1;
+        }
+        bucket.refused
+    }
+
+    /// Forgets about `sender`, which just left the bus.
+    pub fn forget(&self, sender: &str) {
+        self.buckets.borrow_mut().remove(sender);
+    }
+}
+
+/// Fixed fan speed changes from clients which haven't been written yet.
+#[derive(Default)]
+pub(super) struct SpeedWriter {
+    /// The latest hardware speed, if it's waiting for [`WRITE_INTERVAL`].
+    pending: Cell<Option<u8>>,
+    last_write: Cell<Option<Instant>>,
+}
+
+impl SpeedWriter {
+    /// Returns the hardware speed waiting to be written, if any.
+    pub fn pending(&self) -> Option<u8> {
+        self.pending.get()
+    }
+
+    /// Drops the speed waiting to be written, since something else just
+    /// wrote the fixed fan speeds.
+    pub fn cancel(&self) {
+        self.pending.set(None);
+    }
+}
+
+/// Counters for [`Controller::stats`].
+#[derive(Default)]
+pub(super) struct Stats {
+    /// Fixed fan speed changes which were replaced by a newer one before
+    /// they were written.
+    pub coalesced: Cell<u64>,
+    /// Calls refused because their client went over its rate limit.
+    pub rate_limited: Cell<u64>,
+}
+
+/// Returns the error for calls over a client's rate limit.
+fn rate_limited_error() -> dbus::MethodErr {
+    (
+        "org.freedesktop.DBus.Error.LimitsExceeded",
+        "too many calls, slow down",
+    )
+        .into()
+}
+
+impl Controller {
+    /// Fails if the client whose call is being handled has made too many
+    /// calls which change the fans lately. `what` describes the change, for
+    /// the log.
+    pub(super) fn ensure_within_rate(
+        &self,
+        what: std::fmt::Arguments,
+    ) -> Result<(), dbus::MethodErr> {
+        let Some(caller) = &self.caller else {
+            return Ok(());
+        };
+        match self.rate_limiter.take(caller, Instant::now()) {
+            0 => Ok(()),
+            refused => {
+                // Only the first one, so a spammer doesn't spam the log too
+                if refused == 1 {
+                    eprintln!("[warn] {caller} is over its rate limit, refusing to {what}");
+                }
+                self.stats
+                    .rate_limited
+                    .set(self.stats.rate_limited.get() + 1);
+                Err(rate_limited_error())
+            }
+        }
+    }
+
+    /// Writes a fixed fan speed from a client. If one was written less than
+    /// [`WRITE_INTERVAL`] ago, it's written on a later tick instead, unless
+    /// an even newer one replaces it first.
+    pub(super) fn write_fixed_fan_speed_throttled(
+        &self,
+        fixed_fan_speed: f64,
+    ) -> Result<(), ec::EcError> {
+        let writer = &self.speed_writer;
+        let now = Instant::now();
+        let recent = writer
+            .last_write
+            .get()
+            .is_some_and(|last_write| now.duration_since(last_write) < WRITE_INTERVAL);
+        if recent {
+            let hw_speed = fixed_hw_speed(fixed_fan_speed);
+            if writer.pending.replace(Some(hw_speed)).is_some() {
+                self.stats.coalesced.set(self.stats.coalesced.get() + 1);
+            }
+            return Ok(());
+        }
+        writer.last_write.set(Some(now));
+        self.write_fixed_fan_speed(fixed_fan_speed)
+    }
+
+    /// Writes the pending fixed fan speed, if any. Unless `now` is set, this
+    /// waits for [`WRITE_INTERVAL`] to pass since the last write.
+    pub(super) fn flush_fixed_fan_speed(&self, now: bool) -> Result<(), ec::EcError> {
+        let writer = &self.speed_writer;
+        let Some(hw_speed) = writer.pending.get() else {
+            return Ok(());
+        };
+        let last_write = writer.last_write.get();
+        if !now && last_write.is_some_and(|last_write| last_write.elapsed() < WRITE_INTERVAL) {
+            return Ok(());
+        }
+        writer.pending.set(None);
+        writer.last_write.set(Some(Instant::now()));
+        self.ec
+            .borrow_mut()
+            .set_fan_fixed_hw_speeds((hw_speed, hw_speed))
+    }
+
+    /// Returns the server's write and rate limiting counters, as a D-Bus
+    /// dictionary.
+    pub(super) fn stats(&self) -> dbus::arg::PropMap {
+        let ec_stats = self.ec.borrow().write_stats();
+        let mut stats = dbus::arg::PropMap::new();
+        let mut insert = |key: &str, value: u64| {
+            stats.insert(key.to_string(), dbus::arg::Variant(Box::new(value)));
+        };
+        insert("ec_bytes_written", ec_stats.written);
+        insert("ec_bytes_skipped", ec_stats.skipped);
+        insert("coalesced_writes", self.stats.coalesced.get());
+        insert("rate_limited_calls", self.stats.rate_limited.get());
+        stats
+    }
+}
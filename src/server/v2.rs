@@ -0,0 +1,175 @@
+//! The `Controller2` interface, which covers the same ground as the core of
+//! `Controller1` with types that can grow without breaking clients.
+use super::*;
+use dbus::arg::{PropMap, RefArg, Variant};
+
+#[allow(clippy::type_complexity)]
+pub(super) mod server_generated2 {
+    include! { concat!(env!("OUT_DIR"), "/server_generated2.rs") }
+}
+
+/// The optional features advertised through the `Capabilities` property.
+const CAPABILITIES: [&str; 11] = [
+    "timed-override",
+    "schedule",
+    "rules",
+    "power-profiles",
+    "self-test",
+    "calibration",
+    "stall-detection",
+    "external-change-detection",
+    "requests",
+    "inhibit",
+    "stats",
+];
+
+/// Converts a fan mode into its `Controller2` name.
+fn fan_mode_name(fan_mode: FanMode) -> String {
+    match fan_mode {
+        FanMode::Unknown { .. } => "unknown".to_string(),
+        fan_mode => fan_mode.to_string(),
+    }
+}
+
+/// Parses a fan mode sent by a `Controller2` client.
+fn parse_fan_mode(name: &str) -> Result<FanMode, dbus::MethodErr> {
+    name.parse()
+        .map_err(|_| dbus::MethodErr::invalid_arg(&name))
+}
+
+impl server_generated2::ComOffbyondA15kbController2 for Controller {
+    fn interface_version(&self) -> Result<u32, dbus::MethodErr> {
+        Ok(2)
+    }
+    fn capabilities(&self) -> Result<Vec<String>, dbus::MethodErr> {
+        Ok(CAPABILITIES.iter().map(|name| name.to_string()).collect())
+    }
+    fn fan_mode(&self) -> Result<String, dbus::MethodErr> {
+        Ok(fan_mode_name(self.read_fan_mode()?))
+    }
+    fn set_fan_mode(&self, fan_mode: String) -> Result<(), dbus::MethodErr> {
+        self.change_fan_mode(parse_fan_mode(&fan_mode)?)
+    }
+    fn raw_fan_mode_bits(&self) -> Result<(bool, bool, bool), dbus::MethodErr> {
+        Ok(self.ec.borrow_mut().fan_modes()?)
+    }
+    fn fixed_fan_speed(&self) -> Result<f64, dbus::MethodErr> {
+        Ok(self.read_fixed_fan_speed()?)
+    }
+    fn set_fixed_fan_speed(&self, fixed_fan_speed: f64) -> Result<(), dbus::MethodErr> {
+        self.change_fixed_fan_speed(fixed_fan_speed)
+    }
+    fn allowed_fixed_fan_speeds(&self) -> Result<(f64, f64), dbus::MethodErr> {
+        Ok((ec::FAN_FIXED_SPEED_MIN, ec::FAN_FIXED_SPEED_MAX))
+    }
+    fn get_thermal_info(&mut self) -> Result<PropMap, dbus::MethodErr> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as i64);
+        let ec = self.ec.get_mut();
+        let (rpm0, rpm1) = ec.fan_rpm()?;
+        let (hw0, hw1) = ec.fan_fixed_hw_speeds()?;
+        let hw_max = f64::from(ec::HW_MAX_FAN_SPEED);
+        let fixed_fan_speeds = vec![f64::from(hw0) / hw_max, f64::from(hw1) / hw_max];
+        let gpu = self.gpu_check.temp(ec.temp_gpu()?);
+
+        let mut info = PropMap::new();
+        let mut insert = |key: &str, value: Box<dyn RefArg>| {
+            info.insert(key.to_string(), Variant(value));
+        };
+        insert("timestamp_ms", Box::new(timestamp_ms));
+        insert("temp_cpu", Box::new(ec.temp_cpu()?));
+        insert("temp_gpu", Box::new(gpu.temp_or_zero()));
+        insert("gpu", Box::new(gpu.name().to_string()));
+        insert("fan_rpm", Box::new(vec![rpm0, rpm1]));
+        insert("fixed_fan_speeds", Box::new(fixed_fan_speeds));
+        Ok(info)
+    }
+    fn get_capabilities(&mut self) -> Result<PropMap, dbus::MethodErr> {
+        let fan_modes = [
+            FanMode::Quiet,
+            FanMode::Normal,
+            FanMode::Gaming,
+            FanMode::Fixed,
+        ];
+        let fan_modes: Vec<_> = fan_modes.into_iter().map(fan_mode_name).collect();
+        let subsystems: Vec<_> = self
+            .subsystems()
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        let sensors: Vec<_> = self.sensors.names().map(str::to_string).collect();
+
+        let mut caps = PropMap::new();
+        let mut insert = |key: &str, value: Box<dyn RefArg>| {
+            caps.insert(key.to_string(), Variant(value));
+        };
+        insert(
+            "server_version",
+            Box::new(env!("CARGO_PKG_VERSION").to_string()),
+        );
+        insert("model", Box::new(self.model.clone()));
+        insert("fan_modes", Box::new(fan_modes));
+        insert("fan_count", Box::new(2u8));
+        insert("per_fan_control", Box::new(false));
+        insert("sensors", Box::new(sensors));
+        insert("fixed_fan_speed_min", Box::new(ec::FAN_FIXED_SPEED_MIN));
+        insert("fixed_fan_speed_max", Box::new(ec::FAN_FIXED_SPEED_MAX));
+        insert("calibrated", Box::new(self.calibration.is_some()));
+        insert("read_only", Box::new(self.ec.borrow().is_read_only()));
+        insert("subsystems", Box::new(subsystems));
+        Ok(caps)
+    }
+    #[allow(clippy::type_complexity)]
+    fn get_sensors(&mut self) -> Result<Vec<(String, String, String, f64)>, dbus::MethodErr> {
+        let readings = self.sensors.read(self.ec.get_mut());
+        let readings = readings.into_iter().map(|reading| {
+            (
+                reading.name.to_string(),
+                reading.source,
+                reading.unit.to_string(),
+                reading.value.unwrap_or(f64::NAN),
+            )
+        });
+        Ok(readings.collect())
+    }
+    fn set_fan_mode_for(&mut self, fan_mode: String, seconds: u32) -> Result<(), dbus::MethodErr> {
+        self.override_fan_mode(parse_fan_mode(&fan_mode)?, seconds)
+    }
+    fn add_request(
+        &mut self,
+        fan_mode: String,
+        fixed_fan_speed: f64,
+        priority: i32,
+        reason: String,
+    ) -> Result<u32, dbus::MethodErr> {
+        let fan_mode = parse_fan_mode(&fan_mode)?;
+        Controller::add_request(self, fan_mode, fixed_fan_speed, priority, reason)
+    }
+    fn release_request(&mut self, id: u32) -> Result<(), dbus::MethodErr> {
+        Controller::release_request(self, id)
+    }
+    #[allow(clippy::type_complexity)]
+    fn get_requests(
+        &mut self,
+    ) -> Result<Vec<(u32, String, String, f64, i32, String)>, dbus::MethodErr> {
+        let requests = self.requests.sorted().into_iter().map(|request| {
+            (
+                request.id,
+                request.owner.clone(),
+                fan_mode_name(request.fan_mode),
+                request.fixed_fan_speed,
+                request.priority,
+                request.reason.clone(),
+            )
+        });
+        Ok(requests.collect())
+    }
+    fn inhibit(&mut self, reason: String) -> Result<dbus::arg::OwnedFd, dbus::MethodErr> {
+        Controller::inhibit(self, reason)
+    }
+    fn get_stats(&mut self) -> Result<PropMap, dbus::MethodErr> {
+        Ok(self.stats())
+    }
+}
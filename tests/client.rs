@@ -0,0 +1,643 @@
+//! Exercises every `Client` method against a simulated server.
+mod common;
+
+use a15kb::{
+    ExternalChangePolicy, FanMode, GpuTemp, Percent, PowerProfilesCfg, RuleCfg, ScheduleEntryCfg,
+    SensorCfg, ServerCfg, SimStepCfg,
+};
+use common::*;
+use dbus::arg::PropMap;
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The smallest change in fixed fan speed the hardware can represent.
+const HW_STEP: f64 = 1.0 / 229.0;
+
+fn percent(value: f64) -> Percent {
+    Percent::new(value).unwrap()
+}
+
+#[test]
+fn negotiates_version_2() {
+    let server = TestServer::start();
+    assert_eq!(server.client().interface_version().unwrap(), 2);
+}
+
+#[test]
+fn with_connection() {
+    let server = TestServer::start();
+    let client = a15kb::Client::with_connection(server.connection());
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Normal);
+}
+
+#[test]
+fn capabilities() {
+    let server = TestServer::with_cfg(ServerCfg {
+        rules: vec![RuleCfg {
+            name: "never".into(),
+            priority: 0,
+            exe: Some("a15kb-no-such-program".into()),
+            cgroup: None,
+            fan_mode: FanMode::Gaming,
+            fixed_speed: None,
+        }],
+        ..Default::default()
+    });
+    let caps = server.client().capabilities().unwrap();
+    assert_eq!(
+        caps.server_version.as_deref(),
+        Some(env!("CARGO_PKG_VERSION"))
+    );
+    assert_eq!(caps.model.as_deref(), Some("simulated"));
+    assert_eq!(
+        caps.fan_modes,
+        [
+            FanMode::Quiet,
+            FanMode::Normal,
+            FanMode::Gaming,
+            FanMode::Fixed
+        ]
+    );
+    assert_eq!(caps.fan_count, 2);
+    assert!(!caps.per_fan_control);
+    assert_eq!(caps.sensors, ["cpu", "gpu"]);
+    assert_eq!(caps.fixed_fan_speeds, percent(0.3)..=percent(1.0));
+    assert!(!caps.calibrated);
+    for feature in [
+        "self-test",
+        "calibration",
+        "external-change-detection",
+        "requests",
+        "inhibit",
+        "stats",
+    ] {
+        assert!(caps.features.iter().any(|f| f == feature), "{feature}");
+    }
+    assert_eq!(caps.subsystems, ["rules"]);
+}
+
+#[test]
+fn thermal_info() {
+    let server = TestServer::with_steps(vec![SimStepCfg {
+        temp_cpu: Some(72),
+        temp_gpu: Some(64),
+        ..Default::default()
+    }]);
+    let info = server.client().thermal_info().unwrap();
+    assert_eq!(info.temp_cpu, 72);
+    assert_eq!(info.temp_gpu, 64);
+    assert_eq!(info.gpu, GpuTemp::Temp(64));
+    assert!(info.fan_rpm.0 > 0 && info.fan_rpm.1 > 0);
+}
+
+#[test]
+fn thermal_info_gpu_off() {
+    let server = TestServer::start();
+    let info = server.client().thermal_info().unwrap();
+    assert_eq!(info.temp_gpu, 0);
+    assert_eq!(info.gpu, GpuTemp::Off);
+}
+
+#[test]
+fn sensors() {
+    let server = TestServer::with_cfg(ServerCfg {
+        sensors: vec![SensorCfg {
+            name: "also-cpu".into(),
+            ec_offset: Some(0x60),
+            hwmon: None,
+            input: None,
+        }],
+        ..Default::default()
+    });
+    let sensors = server.client().sensors().unwrap();
+    let names: Vec<_> = sensors.iter().map(|sensor| sensor.name.as_str()).collect();
+    assert_eq!(names, ["cpu", "gpu", "also-cpu"]);
+    assert_eq!(sensors[0].source, "ec:0x60");
+    assert_eq!(sensors[0].unit, "celsius");
+    assert_eq!(sensors[0].value, Some(45.0));
+    assert_eq!(sensors[2].value, sensors[0].value);
+}
+
+#[test]
+fn fan_modes_round_trip() {
+    let server = TestServer::start();
+    let client = server.client();
+    for fan_mode in [
+        FanMode::Quiet,
+        FanMode::Gaming,
+        FanMode::Fixed,
+        FanMode::Normal,
+    ] {
+        client.set_fan_mode(fan_mode).unwrap();
+        assert_eq!(client.fan_mode().unwrap(), fan_mode);
+    }
+}
+
+#[test]
+fn fan_mode_through_controller1() {
+    let server = TestServer::start();
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    proxy.set(CONTROLLER1, "FanMode", 2u8).unwrap();
+    let fan_mode: u8 = proxy.get(CONTROLLER1, "FanMode").unwrap();
+    assert_eq!(fan_mode, 2);
+    let fan_mode: String = proxy.get(CONTROLLER2, "FanMode").unwrap();
+    assert_eq!(fan_mode, "gaming");
+    assert_eq!(server.client().fan_mode().unwrap(), FanMode::Gaming);
+}
+
+#[test]
+fn fixed_fan_speed_round_trip() {
+    let server = TestServer::start();
+    let client = server.client();
+    for speed in [0.3, 0.55, 0.8, 1.0] {
+        client.set_fixed_fan_speed(percent(speed)).unwrap();
+        let read = client.fixed_fan_speed().unwrap().as_f64();
+        assert!((read - speed).abs() <= HW_STEP, "set {speed}, read {read}");
+    }
+}
+
+#[test]
+fn fixed_fan_speed_coalesced() {
+    let server = TestServer::start();
+    let client = server.client();
+    // Like dragging a slider
+    for step in 0..=50 {
+        client
+            .set_fixed_fan_speed(percent(0.3 + f64::from(step) * 0.01))
+            .unwrap();
+    }
+    let read = client.fixed_fan_speed().unwrap().as_f64();
+    assert!((read - 0.8).abs() <= HW_STEP, "read {read}");
+    assert!(client.stats().unwrap().coalesced_writes > 0);
+    // The latest speed is written eventually
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    wait_until(Duration::from_secs(5), || {
+        let (info,): (PropMap,) = proxy
+            .method_call(CONTROLLER2, "GetThermalInfo", ())
+            .unwrap();
+        let mut speeds = info["fixed_fan_speeds"].0.as_iter().unwrap();
+        (speeds.next().unwrap().as_f64().unwrap() - 0.8).abs() <= HW_STEP
+    });
+}
+
+#[test]
+fn unchanged_values_not_written() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    let before = client.stats().unwrap();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    let after = client.stats().unwrap();
+    assert_eq!(after.ec_bytes_written, before.ec_bytes_written);
+    assert!(after.ec_bytes_skipped > before.ec_bytes_skipped);
+}
+
+#[test]
+fn fixed_fan_speed_drives_rpm() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.set_fan_mode(FanMode::Fixed).unwrap();
+    client.set_fixed_fan_speed(percent(0.4)).unwrap();
+    let slow = client.thermal_info().unwrap().fan_rpm;
+    // Quick changes are coalesced, so this one takes a moment to be written
+    client.set_fixed_fan_speed(percent(0.9)).unwrap();
+    wait_until(Duration::from_secs(5), || {
+        let fast = client.thermal_info().unwrap().fan_rpm;
+        fast.0 > slow.0 && fast.1 > slow.1
+    });
+}
+
+#[test]
+fn allowed_fixed_fan_speeds() {
+    let server = TestServer::start();
+    let client = server.client();
+    let allowed = client.allowed_fixed_fan_speeds().unwrap();
+    assert_eq!(allowed, percent(0.3)..=percent(1.0));
+
+    // Both ends are allowed...
+    client.set_fixed_fan_speed(*allowed.start()).unwrap();
+    client.set_fixed_fan_speed(*allowed.end()).unwrap();
+
+    // ...and so is everything in between, which the fans then report
+    let (min, max) = (allowed.start().as_f64(), allowed.end().as_f64());
+    for i in 0..=20 {
+        let speed = min + (max - min) * f64::from(i) / 20.0;
+        client.set_fixed_fan_speed(percent(speed)).unwrap();
+        let read = client.fixed_fan_speed().unwrap().as_f64();
+        assert!((read - speed).abs() <= HW_STEP, "set {speed}, read {read}");
+        assert!(
+            read >= min - HW_STEP && read <= max,
+            "{read} is out of range"
+        );
+    }
+
+    // The same range is reported everywhere
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    for interface in [CONTROLLER1, CONTROLLER2] {
+        let range: (f64, f64) = proxy.get(interface, "AllowedFixedFanSpeeds").unwrap();
+        assert_eq!(range, (min, max), "{interface}");
+    }
+    let caps = client.capabilities().unwrap();
+    assert_eq!(caps.fixed_fan_speeds, allowed);
+}
+
+#[test]
+fn fan_mode_override() {
+    let server = TestServer::start();
+    let client = server.client();
+    assert_eq!(client.override_remaining().unwrap(), None);
+
+    client
+        .set_fan_mode_for(FanMode::Gaming, Duration::from_secs(3))
+        .unwrap();
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Gaming);
+    let remaining = client.override_remaining().unwrap().unwrap();
+    assert!(remaining <= Duration::from_secs(3));
+
+    // The previous fan mode comes back afterwards
+    wait_until(Duration::from_secs(10), || {
+        client.fan_mode().unwrap() == FanMode::Normal
+    });
+    assert_eq!(client.override_remaining().unwrap(), None);
+}
+
+#[test]
+fn fan_mode_override_cancelled_by_set_fan_mode() {
+    let server = TestServer::start();
+    let client = server.client();
+    client
+        .set_fan_mode_for(FanMode::Gaming, Duration::from_secs(60))
+        .unwrap();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    assert_eq!(client.override_remaining().unwrap(), None);
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Quiet);
+}
+
+fn following_power_profiles() -> TestServer {
+    TestServer::with_cfg(ServerCfg {
+        power_profiles: PowerProfilesCfg {
+            follow: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+}
+
+#[test]
+fn power_profile_during_override() {
+    let server = following_power_profiles();
+    let client = server.client();
+    client
+        .set_fan_mode_for(FanMode::Gaming, Duration::from_secs(2))
+        .unwrap();
+    server.switch_power_profile("power-saver");
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Gaming);
+
+    // The profile's fan mode comes back afterwards, not the one from before
+    wait_until(Duration::from_secs(10), || {
+        client.override_remaining().unwrap().is_none()
+    });
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Quiet);
+}
+
+#[test]
+fn power_profile_during_self_test() {
+    let server = following_power_profiles();
+    let client = server.client();
+    server.switch_power_profile("power-saver");
+    wait_until(Duration::from_secs(10), || {
+        client.fan_mode().unwrap() == FanMode::Quiet
+    });
+
+    client.start_self_test().unwrap();
+    server.switch_power_profile("performance");
+    std::thread::sleep(Duration::from_millis(500));
+    // The sweep keeps measuring in fixed mode
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Fixed);
+}
+
+#[test]
+fn requests() {
+    let server = TestServer::start();
+    let client = server.client();
+    let other = server.client();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    assert!(client.requests().unwrap().is_empty());
+
+    let low = client
+        .add_request(FanMode::Gaming, None, 0, "compiling")
+        .unwrap();
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Gaming);
+    let high = other
+        .add_request(FanMode::Fixed, Some(percent(0.6)), 10, "recording")
+        .unwrap();
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Fixed);
+    assert!((client.fixed_fan_speed().unwrap().as_f64() - 0.6).abs() <= HW_STEP);
+
+    // The request in effect comes first
+    let requests = client.requests().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].id, high);
+    assert_eq!(requests[0].priority, 10);
+    assert_eq!(requests[0].reason, "recording");
+    assert_eq!(requests[0].fixed_fan_speed, Some(percent(0.6)));
+    assert_eq!(requests[1].id, low);
+    assert_eq!(requests[1].fan_mode, Some(FanMode::Gaming));
+    assert_eq!(requests[1].fixed_fan_speed, None);
+    assert_ne!(requests[0].owner, requests[1].owner);
+
+    other.release_request(high).unwrap();
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Gaming);
+    // The state from before the first request comes back
+    client.release_request(low).unwrap();
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Quiet);
+    assert!(client.requests().unwrap().is_empty());
+}
+
+#[test]
+fn requests_released_on_disconnect() {
+    let server = TestServer::start();
+    let client = server.client();
+    let other = server.client();
+    other
+        .add_request(FanMode::Gaming, None, 0, "going away")
+        .unwrap();
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Gaming);
+    drop(other);
+    wait_until(Duration::from_secs(10), || {
+        client.fan_mode().unwrap() == FanMode::Normal
+    });
+    assert!(client.requests().unwrap().is_empty());
+}
+
+#[test]
+fn inhibit() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    let lock = client.inhibit("recording").unwrap();
+    assert!(client.set_fan_mode(FanMode::Gaming).is_err());
+    // Copies of the fd keep the fans pinned too
+    let copy = lock.try_clone().unwrap();
+    drop(lock);
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(client.set_fan_mode(FanMode::Gaming).is_err());
+    drop(copy);
+    wait_until(Duration::from_secs(10), || {
+        client.set_fan_mode(FanMode::Gaming).is_ok()
+    });
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Gaming);
+}
+
+#[test]
+fn inhibit_thermal_safety_override() {
+    let server = TestServer::with_steps(vec![
+        SimStepCfg {
+            at: 3.0,
+            temp_cpu: Some(95),
+            ..Default::default()
+        },
+        SimStepCfg {
+            at: 6.0,
+            temp_cpu: Some(60),
+            ..Default::default()
+        },
+    ]);
+    let client = server.client();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    let _lock = client.inhibit("recording").unwrap();
+    wait_until(Duration::from_secs(10), || {
+        client.fan_mode().unwrap() == FanMode::Gaming
+    });
+    // The inhibitor gets the fans back once it cools down
+    wait_until(Duration::from_secs(10), || {
+        client.fan_mode().unwrap() == FanMode::Quiet
+    });
+}
+
+#[test]
+fn raw_fan_mode_bits() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    assert_eq!(client.raw_fan_mode_bits().unwrap(), (true, false, false));
+    client.set_fan_mode(FanMode::Fixed).unwrap();
+    assert_eq!(client.raw_fan_mode_bits().unwrap(), (false, false, true));
+}
+
+#[test]
+fn rules() {
+    let server = TestServer::with_cfg(ServerCfg {
+        rules: vec![RuleCfg {
+            name: "compiling".into(),
+            priority: 10,
+            exe: Some("a15kb-no-such-program".into()),
+            cgroup: None,
+            fan_mode: FanMode::Fixed,
+            fixed_speed: Some(0.6),
+        }],
+        ..Default::default()
+    });
+    let client = server.client();
+    let rules = client.rules().unwrap();
+    assert_eq!(rules.len(), 1);
+    assert_eq!(rules[0].name, "compiling");
+    assert_eq!(rules[0].priority, 10);
+    assert_eq!(rules[0].exe.as_deref(), Some("a15kb-no-such-program"));
+    assert_eq!(rules[0].cgroup, None);
+    assert_eq!(rules[0].fan_mode, Some(FanMode::Fixed));
+    assert_eq!(rules[0].fixed_fan_speed, Some(percent(0.6)));
+    assert_eq!(client.active_rule().unwrap(), None);
+}
+
+#[test]
+fn schedule() {
+    let server = TestServer::start();
+    assert!(server.client().next_scheduled_change().unwrap().is_none());
+
+    let at = chrono::Local::now() + chrono::Duration::hours(1);
+    let entry: ScheduleEntryCfg = toml::from_str(&format!(
+        "at = \"{}\"\nmode = \"fixed\"\nfixed_speed = 0.5",
+        at.format("%H:%M")
+    ))
+    .unwrap();
+    let server = TestServer::with_cfg(ServerCfg {
+        schedule: vec![entry],
+        ..Default::default()
+    });
+    let change = server.client().next_scheduled_change().unwrap().unwrap();
+    assert_eq!(change.fan_mode, Some(FanMode::Fixed));
+    assert_eq!(change.fixed_fan_speed, Some(percent(0.5)));
+    let until = change
+        .at
+        .duration_since(std::time::SystemTime::now())
+        .unwrap();
+    assert!(until <= Duration::from_secs(3600));
+}
+
+#[test]
+fn self_test_starts() {
+    let server = TestServer::start();
+    let client = server.client();
+    assert!(!client.self_test_running().unwrap());
+    assert!(client.self_test_results().unwrap().is_none());
+    client.start_self_test().unwrap();
+    assert!(client.self_test_running().unwrap());
+    // The sweep runs in fixed mode
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Fixed);
+}
+
+#[test]
+#[ignore = "takes half a minute"]
+fn self_test_finishes() {
+    let server = TestServer::with_steps(vec![SimStepCfg {
+        stalled: Some(vec![1]),
+        ..Default::default()
+    }]);
+    let client = server.client();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    client.start_self_test().unwrap();
+    wait_until(Duration::from_secs(60), || {
+        !client.self_test_running().unwrap()
+    });
+    let results = client.self_test_results().unwrap().unwrap();
+    assert_eq!(results.steps.len(), 4);
+    assert_eq!(results.health.0, Some(a15kb::FanHealth::Healthy));
+    assert_eq!(results.health.1, Some(a15kb::FanHealth::Stalled));
+    // The fan mode from before the self-test is restored
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Quiet);
+}
+
+#[test]
+fn calibration_starts() {
+    let server = TestServer::start();
+    let client = server.client();
+    assert!(!client.calibrating().unwrap());
+    client.start_calibration().unwrap();
+    assert!(client.calibrating().unwrap());
+}
+
+#[test]
+#[ignore = "takes about a minute"]
+fn calibration_finishes() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.start_calibration().unwrap();
+    wait_until(Duration::from_secs(120), || !client.calibrating().unwrap());
+    assert!(client.capabilities().unwrap().calibrated);
+    assert!(server.state_dir().join("calibration.toml").exists());
+
+    let allowed = client.allowed_fixed_fan_rpms().unwrap();
+    assert!(allowed.start() < allowed.end());
+    let target = (allowed.start() + allowed.end()) / 2;
+    client.set_target_fan_rpm(target).unwrap();
+    let (left, right) = client.fixed_fan_rpm().unwrap();
+    let close = |rpm: u16| rpm.abs_diff(target) < target / 10;
+    assert!(close(left) || close(right), "{left}, {right} vs {target}");
+}
+
+#[test]
+fn external_change_signal() {
+    let server = TestServer::with_steps(vec![SimStepCfg {
+        at: 1.0,
+        fan_mode: Some("gaming".into()),
+        ..Default::default()
+    }]);
+    let client = server.client();
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&changes);
+    client
+        .on_external_change(move |change| sink.lock().unwrap().push(change))
+        .unwrap();
+    process_until(&client, Duration::from_secs(10), || {
+        !changes.lock().unwrap().is_empty()
+    });
+    let change = changes.lock().unwrap()[0];
+    assert_eq!(change.before.fan_mode, FanMode::Normal);
+    assert_eq!(change.after.fan_mode, FanMode::Gaming);
+    assert!(!change.reasserted);
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Gaming);
+}
+
+#[test]
+fn external_change_signal_unknown_fan_mode() {
+    let server = TestServer::with_steps(vec![SimStepCfg {
+        at: 1.0,
+        fan_mode_bits: Some((true, true, false)),
+        ..Default::default()
+    }]);
+    let client = server.client();
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&changes);
+    client
+        .on_external_change(move |change| sink.lock().unwrap().push(change))
+        .unwrap();
+    process_until(&client, Duration::from_secs(10), || {
+        !changes.lock().unwrap().is_empty()
+    });
+    let change = changes.lock().unwrap()[0];
+    assert_eq!(change.before.fan_mode, FanMode::Normal);
+    // The raw bits make it through, so bad states can be shown as they are
+    assert_eq!(
+        change.after.fan_mode,
+        FanMode::Unknown {
+            quiet: true,
+            gaming: true,
+            fixed: false
+        }
+    );
+}
+
+#[test]
+fn external_change_reasserted() {
+    let server = TestServer::with_cfg(ServerCfg {
+        external_changes: ExternalChangePolicy::Reassert,
+        simulation: Some(a15kb::SimCfg {
+            repeat: false,
+            steps: vec![SimStepCfg {
+                at: 1.0,
+                fan_mode: Some("quiet".into()),
+                ..Default::default()
+            }],
+        }),
+        ..Default::default()
+    });
+    let client = server.client();
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&changes);
+    client
+        .on_external_change(move |change| sink.lock().unwrap().push(change))
+        .unwrap();
+    process_until(&client, Duration::from_secs(10), || {
+        !changes.lock().unwrap().is_empty()
+    });
+    assert!(changes.lock().unwrap()[0].reasserted);
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Normal);
+}
+
+#[test]
+fn stall_warning_signal() {
+    let server = TestServer::with_steps(vec![SimStepCfg {
+        stalled: Some(vec![1]),
+        ..Default::default()
+    }]);
+    let client = server.client();
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&warnings);
+    client
+        .on_fan_stall_warning(move |fan, speed| sink.lock().unwrap().push((fan, speed)))
+        .unwrap();
+    client.set_fixed_fan_speed(percent(0.7)).unwrap();
+    client.set_fan_mode(FanMode::Fixed).unwrap();
+    process_until(&client, Duration::from_secs(15), || {
+        !warnings.lock().unwrap().is_empty()
+    });
+    let (fan, speed) = warnings.lock().unwrap()[0];
+    assert_eq!(fan, 1);
+    assert!((speed.as_f64() - 0.7).abs() <= HW_STEP);
+}
@@ -0,0 +1,218 @@
+//! Runs a real server against a simulated embedded controller, on a private
+//! `dbus-daemon`, so tests can talk to it through a real client.
+//!
+//! The daemon is found on `PATH`, or at `$A15KB_DBUS_DAEMON` if that's set.
+#![allow(dead_code)] // not every test file uses everything
+
+use a15kb::{Bus, Client, ServerCfg, SimCfg, SimStepCfg};
+use dbus::arg::{PropMap, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::blocking::{Connection, Proxy};
+use dbus::message::SignalArgs;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The controller's object path, as documented in the interface files.
+pub const CONTROLLER_PATH: &str = "/com/offbyond/a15kb/Controller1";
+pub const CONTROLLER1: &str = "com.offbyond.a15kb.Controller1";
+pub const CONTROLLER2: &str = "com.offbyond.a15kb.Controller2";
+
+/// How long to wait for the server to show up on the bus.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A bus configuration which lets anyone do anything.
+const BUS_CONFIG: &str = r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:dir=DIR</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#;
+
+/// A private bus with a simulated server on it. Everything is torn down when
+/// this is dropped.
+pub struct TestServer {
+    daemon: Child,
+    dir: PathBuf,
+    address: String,
+}
+
+impl TestServer {
+    /// Starts a server with the default configuration, whose simulated
+    /// laptop just idles.
+    pub fn start() -> Self {
+        Self::with_cfg(ServerCfg::default())
+    }
+
+    /// Starts a server whose simulated laptop runs the given steps.
+    pub fn with_steps(steps: Vec<SimStepCfg>) -> Self {
+        Self::with_cfg(ServerCfg {
+            simulation: Some(SimCfg {
+                repeat: false,
+                steps,
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// Starts a server with the given configuration. The bus and state
+    /// directory are always replaced, and the embedded controller is always
+    /// simulated.
+    pub fn with_cfg(cfg: ServerCfg) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "a15kb-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("bus.conf");
+        let config = BUS_CONFIG.replace("DIR", dir.to_str().unwrap());
+        std::fs::write(&config_path, config).unwrap();
+
+        let program = std::env::var("A15KB_DBUS_DAEMON").unwrap_or_else(|_| "dbus-daemon".into());
+        let mut daemon = Command::new(&program)
+            .arg(format!("--config-file={}", config_path.display()))
+            .arg("--nofork")
+            .arg("--print-address")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|err| panic!("couldn't start {program}: {err}"));
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_string();
+        assert!(!address.is_empty(), "dbus-daemon didn't print its address");
+
+        let cfg = ServerCfg {
+            bus: Bus::Address(address.clone()),
+            simulation: Some(cfg.simulation.unwrap_or_default()),
+            state_dir: dir.join("state"),
+            ..cfg
+        };
+        // The server stops once the daemon is killed, since it loses its
+        // connection
+        std::thread::spawn(move || a15kb::run_server(&cfg));
+
+        let server = Self {
+            daemon,
+            dir,
+            address,
+        };
+        server.wait_for_server();
+        server
+    }
+
+    fn wait_for_server(&self) {
+        let conn = self.connection();
+        let started = Instant::now();
+        loop {
+            let proxy = conn.with_proxy("org.freedesktop.DBus", "/", Duration::from_secs(1));
+            let (has_owner,): (bool,) = proxy
+                .method_call("org.freedesktop.DBus", "NameHasOwner", (a15kb::BUS_NAME,))
+                .unwrap();
+            if has_owner {
+                return;
+            }
+            assert!(
+                started.elapsed() < STARTUP_TIMEOUT,
+                "the server never started"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Returns the address of the private bus.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Returns the server's state directory.
+    pub fn state_dir(&self) -> PathBuf {
+        self.dir.join("state")
+    }
+
+    /// Connects a new client to the server.
+    pub fn client(&self) -> Client {
+        Client::with_address(&self.address).unwrap()
+    }
+
+    /// Opens a new raw connection to the private bus.
+    pub fn connection(&self) -> Connection {
+        Bus::Address(self.address.clone()).connect().unwrap()
+    }
+
+    /// Pretends to be `power-profiles-daemon` switching to `profile`.
+    pub fn switch_power_profile(&self, profile: &str) {
+        let conn = self.connection();
+        conn.request_name("net.hadess.PowerProfiles", false, false, false)
+            .unwrap();
+        let mut changed_properties = PropMap::new();
+        changed_properties.insert(
+            "ActiveProfile".into(),
+            Variant(Box::new(profile.to_string())),
+        );
+        let changed = PropertiesPropertiesChanged {
+            interface_name: "net.hadess.PowerProfiles".into(),
+            changed_properties,
+            invalidated_properties: Vec::new(),
+        };
+        conn.channel()
+            .send(changed.to_emit_message(&"/net/hadess/PowerProfiles".into()))
+            .unwrap();
+        // The bus delivers it before noticing we're gone and releasing the
+        // name
+        conn.channel().flush();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Returns a proxy for the controller, for talking to it without the
+/// client.
+pub fn controller(conn: &Connection) -> Proxy<'_, &Connection> {
+    conn.with_proxy(a15kb::BUS_NAME, CONTROLLER_PATH, Duration::from_secs(1))
+}
+
+/// Returns the name of a D-Bus error, e.g.
+/// `org.freedesktop.DBus.Error.InvalidArgs`.
+pub fn error_name(err: &dbus::Error) -> &str {
+    err.name().unwrap_or("")
+}
+
+/// Processes incoming messages until `done` returns true, failing if that
+/// takes longer than `timeout`.
+pub fn process_until(client: &Client, timeout: Duration, mut done: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < timeout, "timed out");
+        client.process(Duration::from_millis(100)).unwrap();
+    }
+}
+
+/// Polls `f` until it returns true, failing if that takes longer than
+/// `timeout`.
+pub fn wait_until(timeout: Duration, mut f: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !f() {
+        assert!(started.elapsed() < timeout, "timed out");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
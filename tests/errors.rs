@@ -0,0 +1,398 @@
+//! Error paths, and what the server makes of every combination of fan mode
+//! register bits.
+mod common;
+
+use a15kb::{ExternalChangePolicy, FanMode, Percent, RateLimitCfg, ServerCfg, SimCfg, SimStepCfg};
+use common::*;
+use dbus::arg::PropMap;
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use std::time::Duration;
+
+const INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+const BUSY: &str = "com.offbyond.a15kb.Error.Busy";
+const NOT_CALIBRATED: &str = "com.offbyond.a15kb.Error.NotCalibrated";
+const READ_ONLY: &str = "com.offbyond.a15kb.Error.ReadOnly";
+const REQUESTED: &str = "com.offbyond.a15kb.Error.Requested";
+const ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+const INHIBITED: &str = "com.offbyond.a15kb.Error.Inhibited";
+const LIMITS_EXCEEDED: &str = "org.freedesktop.DBus.Error.LimitsExceeded";
+
+#[test]
+fn invalid_fan_mode_discriminants() {
+    let server = TestServer::start();
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    for discriminant in 4..=u8::MAX {
+        let err = proxy.set(CONTROLLER1, "FanMode", discriminant).unwrap_err();
+        assert_eq!(error_name(&err), INVALID_ARGS, "{discriminant}");
+        let err = proxy
+            .method_call::<(), _, _, _>(CONTROLLER1, "SetFanModeFor", (discriminant, 10u32))
+            .unwrap_err();
+        assert_eq!(error_name(&err), INVALID_ARGS, "{discriminant}");
+    }
+    // Nothing changed
+    assert_eq!(server.client().fan_mode().unwrap(), FanMode::Normal);
+}
+
+#[test]
+fn invalid_fan_mode_names() {
+    let server = TestServer::start();
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    for name in ["", "unknown", "turbo", "quiet "] {
+        let err = proxy.set(CONTROLLER2, "FanMode", name).unwrap_err();
+        assert_eq!(error_name(&err), INVALID_ARGS, "{name:?}");
+    }
+    // Names are case-insensitive, though
+    proxy.set(CONTROLLER2, "FanMode", "QUIET").unwrap();
+    assert_eq!(server.client().fan_mode().unwrap(), FanMode::Quiet);
+}
+
+#[test]
+fn unknown_fan_mode_cannot_be_set() {
+    let server = TestServer::start();
+    let client = server.client();
+    let unknown = FanMode::Unknown {
+        quiet: true,
+        gaming: true,
+        fixed: false,
+    };
+    let err = client.set_fan_mode(unknown).unwrap_err();
+    assert_eq!(error_name(&err), INVALID_ARGS);
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Normal);
+}
+
+#[test]
+fn out_of_range_fixed_fan_speeds() {
+    let server = TestServer::start();
+    let client = server.client();
+    client
+        .set_fixed_fan_speed(Percent::new(0.5).unwrap())
+        .unwrap();
+    for speed in [0.0, 0.1, 0.29, 1.01, 1.5, 100.0] {
+        let err = client
+            .set_fixed_fan_speed(Percent::new(speed).unwrap())
+            .unwrap_err();
+        assert_eq!(error_name(&err), INVALID_ARGS, "{speed}");
+    }
+
+    // Percent can't be negative or NaN, but the interfaces can be sent them
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    for interface in [CONTROLLER1, CONTROLLER2] {
+        for speed in [-0.5, f64::NAN, f64::INFINITY] {
+            let err = proxy.set(interface, "FixedFanSpeed", speed).unwrap_err();
+            assert_eq!(error_name(&err), INVALID_ARGS, "{interface} {speed}");
+        }
+    }
+
+    // Nothing changed
+    let speed = client.fixed_fan_speed().unwrap().as_f64();
+    assert!((speed - 0.5).abs() < 0.01, "{speed}");
+}
+
+#[test]
+fn zero_length_override() {
+    let server = TestServer::start();
+    let client = server.client();
+    let err = client
+        .set_fan_mode_for(FanMode::Gaming, Duration::ZERO)
+        .unwrap_err();
+    assert_eq!(error_name(&err), INVALID_ARGS);
+    assert_eq!(client.override_remaining().unwrap(), None);
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Normal);
+}
+
+#[test]
+fn busy_during_sweeps() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.start_self_test().unwrap();
+
+    let percent = Percent::new(0.5).unwrap();
+    let errors = [
+        client.set_fan_mode(FanMode::Quiet).unwrap_err(),
+        client.set_fixed_fan_speed(percent).unwrap_err(),
+        client
+            .set_fan_mode_for(FanMode::Quiet, Duration::from_secs(10))
+            .unwrap_err(),
+        client.start_self_test().unwrap_err(),
+        client.start_calibration().unwrap_err(),
+    ];
+    for err in errors {
+        assert_eq!(error_name(&err), BUSY, "{err}");
+    }
+    // Reading still works
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Fixed);
+    client.thermal_info().unwrap();
+}
+
+#[test]
+fn not_calibrated() {
+    let server = TestServer::start();
+    let client = server.client();
+    let errors = [
+        client.fixed_fan_rpm().unwrap_err(),
+        client.set_target_fan_rpm(3000).unwrap_err(),
+        client.allowed_fixed_fan_rpms().unwrap_err(),
+    ];
+    for err in errors {
+        assert_eq!(error_name(&err), NOT_CALIBRATED, "{err}");
+    }
+}
+
+#[test]
+fn read_only() {
+    let server = TestServer::with_cfg(ServerCfg {
+        read_only: true,
+        ..Default::default()
+    });
+    let client = server.client();
+    assert!(client.capabilities().unwrap().read_only);
+
+    let percent = Percent::new(0.5).unwrap();
+    let errors = [
+        client.set_fan_mode(FanMode::Quiet).unwrap_err(),
+        client.set_fixed_fan_speed(percent).unwrap_err(),
+        client
+            .set_fan_mode_for(FanMode::Quiet, Duration::from_secs(10))
+            .unwrap_err(),
+        client.start_self_test().unwrap_err(),
+        client.start_calibration().unwrap_err(),
+        client.inhibit("recording").unwrap_err(),
+        client
+            .add_request(FanMode::Quiet, None, 0, "meeting")
+            .unwrap_err(),
+    ];
+    for err in errors {
+        assert_eq!(error_name(&err), READ_ONLY, "{err}");
+    }
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    let err = proxy.set(CONTROLLER2, "FanMode", "gaming").unwrap_err();
+    assert_eq!(error_name(&err), READ_ONLY);
+
+    // Everything can still be read, and nothing changed
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Normal);
+    client.thermal_info().unwrap();
+    client.sensors().unwrap();
+    assert!(!client.self_test_running().unwrap());
+}
+
+#[test]
+fn no_server() {
+    let server = TestServer::start();
+    let conn = server.connection();
+    let proxy = conn.with_proxy(
+        "com.offbyond.a15kb.Nobody",
+        CONTROLLER_PATH,
+        Duration::from_secs(1),
+    );
+    let err = proxy.get::<u8>(CONTROLLER1, "FanMode").unwrap_err();
+    assert_eq!(
+        error_name(&err),
+        "org.freedesktop.DBus.Error.ServiceUnknown"
+    );
+
+    // Clients report the server going away as an error, rather than hanging
+    let client = server.client();
+    client.fan_mode().unwrap();
+    drop(server);
+    assert!(client.fan_mode().is_err());
+}
+
+#[test]
+fn every_fan_mode_bit_combination() {
+    for quiet in [false, true] {
+        for gaming in [false, true] {
+            for fixed in [false, true] {
+                check_fan_mode_bits((quiet, gaming, fixed));
+            }
+        }
+    }
+}
+
+/// Starts a server whose fan mode bits are `bits`, and checks what it makes
+/// of them.
+fn check_fan_mode_bits(bits: (bool, bool, bool)) {
+    let expected = match bits {
+        (false, false, false) => FanMode::Normal,
+        (true, false, false) => FanMode::Quiet,
+        (false, true, false) => FanMode::Gaming,
+        // The fixed bit wins over everything else
+        (_, _, true) => FanMode::Fixed,
+        (quiet, gaming, fixed) => FanMode::Unknown {
+            quiet,
+            gaming,
+            fixed,
+        },
+    };
+    let server = TestServer::with_steps(vec![SimStepCfg {
+        fan_mode_bits: Some(bits),
+        ..Default::default()
+    }]);
+    let client = server.client();
+    assert_eq!(client.fan_mode().unwrap(), expected, "{bits:?}");
+    assert_eq!(client.raw_fan_mode_bits().unwrap(), bits);
+
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    let v1: u8 = proxy.get(CONTROLLER1, "FanMode").unwrap();
+    assert_eq!(v1, expected.to_discriminant(), "{bits:?}");
+    let v2: String = proxy.get(CONTROLLER2, "FanMode").unwrap();
+    let name = match expected {
+        FanMode::Unknown { .. } => "unknown".to_string(),
+        fan_mode => fan_mode.to_string(),
+    };
+    assert_eq!(v2, name, "{bits:?}");
+    for interface in [CONTROLLER1, CONTROLLER2] {
+        let raw: (bool, bool, bool) = proxy.get(interface, "RawFanModeBits").unwrap();
+        assert_eq!(raw, bits, "{interface}");
+    }
+
+    // Setting a fan mode always repairs the bits
+    client.set_fan_mode(FanMode::Normal).unwrap();
+    assert_eq!(client.raw_fan_mode_bits().unwrap(), (false, false, false));
+}
+
+#[test]
+fn thermal_info_dictionary() {
+    // Clients are told to ignore keys they don't know, but the documented
+    // ones have to be there with the documented types
+    let server = TestServer::start();
+    let conn = server.connection();
+    let proxy = controller(&conn);
+    let (info,): (PropMap,) = proxy
+        .method_call(CONTROLLER2, "GetThermalInfo", ())
+        .unwrap();
+    let signature = |key: &str| info[key].0.signature().to_string();
+    assert_eq!(signature("timestamp_ms"), "x");
+    assert_eq!(signature("temp_cpu"), "y");
+    assert_eq!(signature("temp_gpu"), "y");
+    assert_eq!(signature("gpu"), "s");
+    assert_eq!(signature("fan_rpm"), "aq");
+    assert_eq!(signature("fixed_fan_speeds"), "ad");
+}
+
+#[test]
+fn invalid_fan_mode_bits_reasserted_as_normal() {
+    // The bits found at startup count as written, but quiet+gaming can't be
+    // written back
+    let server = TestServer::with_cfg(ServerCfg {
+        external_changes: ExternalChangePolicy::Reassert,
+        simulation: Some(SimCfg {
+            repeat: false,
+            steps: vec![
+                SimStepCfg {
+                    fan_mode_bits: Some((true, true, false)),
+                    ..Default::default()
+                },
+                SimStepCfg {
+                    at: 1.0,
+                    fan_mode: Some("gaming".into()),
+                    ..Default::default()
+                },
+            ],
+        }),
+        ..Default::default()
+    });
+    let client = server.client();
+    wait_until(Duration::from_secs(10), || {
+        client.fan_mode().unwrap() == FanMode::Normal
+    });
+}
+
+#[test]
+fn requested() {
+    let server = TestServer::start();
+    let client = server.client();
+    let other = server.client();
+    let id = client
+        .add_request(FanMode::Quiet, None, 0, "meeting")
+        .unwrap();
+
+    // Nobody can change the fans directly while a request is in effect
+    let percent = Percent::new(0.5).unwrap();
+    let errors = [
+        other.set_fan_mode(FanMode::Gaming).unwrap_err(),
+        other.set_fixed_fan_speed(percent).unwrap_err(),
+        client.set_fan_mode(FanMode::Gaming).unwrap_err(),
+        other
+            .set_fan_mode_for(FanMode::Gaming, Duration::from_secs(10))
+            .unwrap_err(),
+        other.start_self_test().unwrap_err(),
+    ];
+    for err in errors {
+        assert_eq!(error_name(&err), REQUESTED, "{err}");
+    }
+    // ...and the error says who's holding them
+    let err = other.set_fan_mode(FanMode::Gaming).unwrap_err();
+    assert!(err.message().unwrap().contains("meeting"), "{err}");
+
+    // Only the client which made a request can release it
+    let err = other.release_request(id).unwrap_err();
+    assert_eq!(error_name(&err), ACCESS_DENIED);
+    let err = other.release_request(id + 1).unwrap_err();
+    assert_eq!(error_name(&err), INVALID_ARGS);
+    let err = other
+        .add_request(FanMode::Fixed, Percent::new(2.0), 0, "too fast")
+        .unwrap_err();
+    assert_eq!(error_name(&err), INVALID_ARGS);
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Quiet);
+
+    client.release_request(id).unwrap();
+    other.set_fan_mode(FanMode::Gaming).unwrap();
+}
+
+#[test]
+fn inhibited() {
+    let server = TestServer::start();
+    let client = server.client();
+    let other = server.client();
+    client.set_fan_mode(FanMode::Quiet).unwrap();
+    let _lock = client.inhibit("recording").unwrap();
+
+    // Not even the client holding the lock can change the fans
+    let percent = Percent::new(0.5).unwrap();
+    let errors = [
+        other.set_fan_mode(FanMode::Gaming).unwrap_err(),
+        other.set_fixed_fan_speed(percent).unwrap_err(),
+        client.set_fan_mode(FanMode::Gaming).unwrap_err(),
+        other
+            .set_fan_mode_for(FanMode::Gaming, Duration::from_secs(10))
+            .unwrap_err(),
+        other
+            .add_request(FanMode::Gaming, None, 100, "compiling")
+            .unwrap_err(),
+        other.start_self_test().unwrap_err(),
+    ];
+    for err in errors {
+        assert_eq!(error_name(&err), INHIBITED, "{err}");
+    }
+    // ...and the error says who's holding them
+    let err = other.set_fan_mode(FanMode::Gaming).unwrap_err();
+    assert!(err.message().unwrap().contains("recording"), "{err}");
+    assert_eq!(client.fan_mode().unwrap(), FanMode::Quiet);
+}
+
+#[test]
+fn rate_limited() {
+    let server = TestServer::with_cfg(ServerCfg {
+        rate_limit: RateLimitCfg {
+            burst: 5,
+            per_second: 0.0,
+        },
+        ..Default::default()
+    });
+    let client = server.client();
+    for _ in 0..5 {
+        client.set_fan_mode(FanMode::Quiet).unwrap();
+    }
+    let err = client.set_fan_mode(FanMode::Quiet).unwrap_err();
+    assert_eq!(error_name(&err), LIMITS_EXCEEDED);
+
+    // Other clients have their own limits
+    let other = server.client();
+    other.set_fan_mode(FanMode::Gaming).unwrap();
+    assert_eq!(other.stats().unwrap().rate_limited_calls, 1);
+}
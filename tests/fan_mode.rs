@@ -0,0 +1,66 @@
+//! The fan mode discriminants are part of the D-Bus interface, so make sure
+//! the Rust API and the interface's documentation agree on them.
+use a15kb::FanMode;
+
+const KNOWN: [FanMode; 4] = [
+    FanMode::Quiet,
+    FanMode::Normal,
+    FanMode::Gaming,
+    FanMode::Fixed,
+];
+
+#[test]
+fn discriminants_round_trip() {
+    for fan_mode in KNOWN {
+        let discriminant = fan_mode.to_discriminant();
+        assert_eq!(FanMode::from_discriminant(discriminant), Some(fan_mode));
+    }
+    for discriminant in 4..=u8::MAX {
+        assert_eq!(FanMode::from_discriminant(discriminant), None);
+    }
+}
+
+#[test]
+fn unknown_is_255() {
+    let unknown = FanMode::Unknown {
+        quiet: true,
+        gaming: true,
+        fixed: false,
+    };
+    assert_eq!(unknown.to_discriminant(), 255);
+    assert_eq!(unknown.to_string(), "unknown (quiet+gaming)");
+    assert!(unknown.to_string().parse::<FanMode>().is_err());
+}
+
+#[test]
+fn names_round_trip() {
+    for fan_mode in KNOWN {
+        assert_eq!(fan_mode.to_string().parse::<FanMode>().unwrap(), fan_mode);
+        let upper = fan_mode.to_string().to_uppercase();
+        assert_eq!(upper.parse::<FanMode>().unwrap(), fan_mode);
+    }
+}
+
+#[test]
+fn interface_docs_match() {
+    // e.g. "The fan mode: 0 = quiet, 1 = normal, ..., 255 = unknown."
+    let xml = include_str!("../a15kb.Controller1.xml");
+    let (_, table) = xml
+        .split_once("The fan mode: ")
+        .expect("FanMode is undocumented");
+    let (table, _) = table.split_once('.').unwrap();
+    let mut documented = 0;
+    for entry in table.split(", ") {
+        let (discriminant, name) = entry.split_once(" = ").unwrap();
+        let discriminant: u8 = discriminant.parse().unwrap();
+        match name {
+            "unknown" => assert_eq!(discriminant, 255),
+            name => {
+                let fan_mode = FanMode::from_discriminant(discriminant);
+                assert_eq!(fan_mode, Some(name.parse().unwrap()), "{entry}");
+                documented += 1;
+            }
+        }
+    }
+    assert_eq!(documented, KNOWN.len());
+}